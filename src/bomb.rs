@@ -0,0 +1,113 @@
+use bevy::prelude::*;
+use crate::controls::PlayerInput;
+use crate::powerup::{spawn_pickup, Powerup};
+use crate::{
+    destroy_enemy, simulation, SimulationStep, ActiveEnemies, Enemy, EnemyKilled, FromEnemy, GameClock, Health, HitFlash,
+    Invulnerable, Laser, Player, PlayerState, Points, Shielded, SpriteInfos, BOMB_DAMAGE,
+    BOMB_INVULNERABLE_SECONDS, FIXED_UPDATE, HIT_FLASH_COLOR, HIT_FLASH_SECONDS, SHOCKWAVE_SECONDS,
+};
+
+pub struct BombPlugin;
+
+// explosion frame the shockwave is drawn with, and how much it grows
+const SHOCKWAVE_FRAME: usize = 6;
+const SHOCKWAVE_SCALE: f32 = 16.;
+
+/// Expanding ring left by a bomb, purely visual
+#[derive(Component)]
+pub struct Shockwave(Timer);
+
+impl Plugin for BombPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set_to_stage(
+            FIXED_UPDATE,
+            simulation(SimulationStep::Bomb)
+                .with_system(bomb_trigger.system().label("bomb_trigger").label("collisions"))
+                .with_system(shockwave_grow.system().after("bomb_trigger")),
+        );
+    }
+}
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn bomb_trigger(
+    mut commands: Commands,
+    input: Res<PlayerInput>,
+    sprite_infos: Res<SpriteInfos>,
+    mut player_state: ResMut<PlayerState>,
+    mut active_enemies: ResMut<ActiveEnemies>,
+    mut enemy_killed: EventWriter<EnemyKilled>,
+    player_query: Query<(Entity, &Transform), With<Player>>,
+    laser_query: Query<(Entity, &Transform), (With<Laser>, With<FromEnemy>)>,
+    mut enemy_query: Query<(Entity, &Enemy, &Transform, &Points, &mut Health, &mut Sprite, Option<&Shielded>)>,
+) {
+    if !input.bomb_pressed() || player_state.bombs == 0 {
+        return;
+    }
+    let (player_entity, player_tf) = match player_query.get_single() {
+        Ok(player) => player,
+        Err(_) => return,
+    };
+    player_state.bombs -= 1;
+
+    // every enemy laser on screen turns into a score item
+    for (laser_entity, laser_tf) in laser_query.iter() {
+        commands.entity(laser_entity).despawn();
+        spawn_pickup(&mut commands, Powerup::ScoreItem, laser_tf.translation);
+    }
+
+    for (enemy_entity, enemy, enemy_tf, points, mut health, mut sprite, shielded) in enemy_query.iter_mut() {
+        // enemies without health left were destroyed earlier this tick and wait for their despawn
+        if shielded.is_some() || health.0 == 0 {
+            continue;
+        }
+        health.0 = health.0.saturating_sub(BOMB_DAMAGE);
+        if health.0 > 0 {
+            sprite.color = HIT_FLASH_COLOR;
+            commands
+                .entity(enemy_entity)
+                .insert(HitFlash(Timer::from_seconds(HIT_FLASH_SECONDS, false)));
+        } else {
+            destroy_enemy(
+                &mut commands,
+                enemy_entity,
+                enemy,
+                enemy_tf.translation,
+                points.0,
+                &mut active_enemies,
+                &mut enemy_killed,
+            );
+        }
+    }
+
+    commands
+        .entity(player_entity)
+        .insert(Invulnerable(Timer::from_seconds(BOMB_INVULNERABLE_SECONDS, false)));
+
+    commands
+        .spawn_bundle(SpriteSheetBundle {
+            texture_atlas: sprite_infos.explosion.clone(),
+            sprite: TextureAtlasSprite::new(SHOCKWAVE_FRAME),
+            transform: Transform {
+                translation: Vec3::new(player_tf.translation.x, player_tf.translation.y, 20.),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .insert(Shockwave(Timer::from_seconds(SHOCKWAVE_SECONDS, false)));
+}
+
+fn shockwave_grow(
+    mut commands: Commands,
+    clock: Res<GameClock>,
+    mut query: Query<(Entity, &mut Shockwave, &mut Transform, &mut TextureAtlasSprite)>,
+) {
+    for (entity, mut shockwave, mut tf, mut sprite) in query.iter_mut() {
+        if shockwave.0.tick(clock.delta()).finished() {
+            commands.entity(entity).despawn();
+            continue;
+        }
+        let t = shockwave.0.elapsed_secs() / SHOCKWAVE_SECONDS;
+        tf.scale = Vec3::splat(1. + t * SHOCKWAVE_SCALE);
+        sprite.color = Color::rgba(1., 1., 1., 1. - t);
+    }
+}
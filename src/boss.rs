@@ -0,0 +1,187 @@
+use bevy::prelude::*;
+use crate::bulletml::BulletMlRunner;
+use crate::defs::{BossDef, BossMovementDef, GameDefs, PhaseDef};
+use crate::pattern::Emitter;
+use crate::wave::WaveDirector;
+use crate::{
+    simulation, SimulationStep, ActiveEnemies, Enemy, GameClock, Health, Points, PlayField, Shielded, Speed, SpriteInfos, SpriteSize, WinSize,
+    BOSS_TRANSITION, FIXED_UPDATE, TIME_STEP,
+};
+
+pub struct BossPlugin;
+
+const BOSS_ENTRY_SPEED: f32 = 150.;
+
+// region:    Boss
+// Component
+#[derive(Component)]
+pub struct Boss {
+    name: String,
+    max_health: u32,
+    phases: Vec<PhaseDef>,
+    phase: usize,
+    phase_time: f32,
+    transition: Timer,
+}
+
+impl Boss {
+    fn new(def: &BossDef) -> Self {
+        let mut boss = Self {
+            name: def.name.clone(),
+            max_health: def.health,
+            phases: def.phases.clone(),
+            phase: 0,
+            phase_time: 0.,
+            transition: Timer::default(),
+        };
+        boss.enter_phase(0);
+        boss
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn max_health(&self) -> u32 {
+        self.max_health
+    }
+
+    fn current(&self) -> &PhaseDef {
+        &self.phases[self.phase]
+    }
+
+    /// Between phases the boss is shielded, holds fire and moves back to its home position
+    fn in_transition(&self) -> bool {
+        !self.transition.finished()
+    }
+
+    fn enter_phase(&mut self, phase: usize) {
+        self.phase = phase;
+        self.phase_time = 0.;
+        self.transition = Timer::from_seconds(BOSS_TRANSITION, false);
+    }
+}
+// endregion: Boss
+
+impl Plugin for BossPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set_to_stage(
+            FIXED_UPDATE,
+            simulation(SimulationStep::Boss)
+                .with_system(boss_spawn.system().label("boss_spawn"))
+                .with_system(boss_phase.system().label("boss_phase").after("boss_spawn"))
+                .with_system(boss_movement.system().after("boss_phase")),
+        );
+    }
+}
+
+fn boss_home(field: &PlayField) -> Vec3 {
+    Vec3::new(0., field.h / 2. - 120., 10.)
+}
+
+/// The wave boss enters once every other enemy of the wave is destroyed
+fn boss_spawn(
+    mut commands: Commands,
+    mut active_enemies: ResMut<ActiveEnemies>,
+    mut wave_director: ResMut<WaveDirector>,
+    win_size: Res<WinSize>,
+    materials: Res<SpriteInfos>,
+    defs: Res<GameDefs>,
+) {
+    if active_enemies.0 > 0 {
+        return;
+    }
+    if let Some(name) = wave_director.take_boss() {
+        let def = defs.boss(&name);
+        let (texture, size) = materials.bosses[&def.name].clone();
+        commands
+            .spawn_bundle(SpriteBundle {
+                texture,
+                transform: Transform {
+                    translation: Vec3::new(0., win_size.h / 2. + size.y * def.scale, 10.),
+                    scale: Vec3::new(def.scale, def.scale, 1.),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .insert(Enemy::Boss)
+            .insert(Boss::new(def))
+            .insert(Health(def.health))
+            .insert(Points(def.points))
+            .insert(SpriteSize(size))
+            .insert(Speed(BOSS_ENTRY_SPEED))
+            .insert(Shielded);
+
+        active_enemies.0 += 1;
+    }
+}
+
+/// Switch to the next phase when health drops to the current phase threshold,
+/// the phase patterns start firing once the transition is over
+fn boss_phase(
+    mut commands: Commands,
+    clock: Res<GameClock>,
+    defs: Res<GameDefs>,
+    mut query: Query<(Entity, &mut Boss, &Health, &mut Sprite)>,
+) {
+    for (entity, mut boss, health, mut sprite) in query.iter_mut() {
+        if boss.in_transition() {
+            boss.transition.tick(clock.delta());
+            if boss.transition.finished() {
+                sprite.color = Color::WHITE;
+                let mut boss_entity = commands.entity(entity);
+                boss_entity.remove::<Shielded>().insert(Emitter::new(&boss.current().fire));
+                if let Some(file) = &boss.current().bulletml {
+                    boss_entity.insert(BulletMlRunner::new(defs.bulletml(file)));
+                }
+            } else {
+                // blink while shielded
+                let blink = ((boss.transition.elapsed_secs() * 10.) as u32).is_multiple_of(2);
+                sprite.color.set_a(if blink { 1. } else { 0.4 });
+            }
+            continue;
+        }
+
+        let fraction = health.0 as f32 / boss.max_health as f32;
+        if boss.phase + 1 < boss.phases.len() && fraction <= boss.current().until {
+            let next = boss.phase + 1;
+            boss.enter_phase(next);
+            commands
+                .entity(entity)
+                .remove::<Emitter>()
+                .remove::<BulletMlRunner>()
+                .insert(Shielded);
+        }
+    }
+}
+
+fn boss_movement(field: Res<PlayField>, mut query: Query<(&mut Boss, &Speed, &mut Transform)>) {
+    let home = boss_home(&field);
+    for (mut boss, speed, mut tf) in query.iter_mut() {
+        if boss.in_transition() {
+            let to_home = home - tf.translation;
+            let step = speed.0 * TIME_STEP;
+            if to_home.length() <= step {
+                tf.translation = home;
+            } else {
+                tf.translation += to_home.normalize() * step;
+            }
+            continue;
+        }
+
+        // every movement starts at the home position
+        boss.phase_time += TIME_STEP;
+        let t = boss.phase_time;
+        tf.translation = match boss.current().movement {
+            BossMovementDef::Hover => home,
+            BossMovementDef::Sweep { speed } => {
+                let amplitude = field.w / 2. - 100.;
+                home + Vec3::X * amplitude * (t * speed / amplitude).sin()
+            }
+            BossMovementDef::Circle { radius, speed } => {
+                let angle = t * speed / radius;
+                home + Vec3::new(radius * (angle.cos() - 1.), radius * angle.sin(), 0.)
+            }
+        };
+    }
+}
@@ -0,0 +1,1072 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use bevy::prelude::*;
+use rand::{Rng, RngCore};
+use crate::defs::BulletDef;
+use crate::pattern::{spawn_bullet, Bullet};
+use crate::wave::WaveDirector;
+use crate::{simulation, SimulationStep, GameRng, Player, SpriteInfos, FIXED_UPDATE, TIME_STEP};
+
+pub struct BulletMlPlugin;
+
+// a script that never waits could otherwise freeze the tick
+const MAX_STEPS_PER_TICK: u32 = 10_000;
+// an action referencing itself would otherwise grow a thread without end
+const MAX_ACTION_DEPTH: usize = 64;
+
+// region:    Document
+// BulletML angles are degrees, 0 up and clockwise, speeds are pixels per frame
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Num(f32),
+    Rand,
+    Rank,
+    Param(usize),
+    Neg(Box<Expr>),
+    Bin(Op, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Aim,
+    Absolute,
+    Relative,
+    Sequence,
+}
+
+#[derive(Debug, Clone)]
+struct Value {
+    kind: Kind,
+    expr: Expr,
+}
+
+/// Reference to an action, fire or bullet, inline elements have no parameters
+/// of their own and share the ones of the element they are in
+#[derive(Debug, Clone, Default)]
+struct Ref {
+    index: usize,
+    params: Option<Vec<Expr>>,
+}
+
+#[derive(Debug, Clone)]
+enum Step {
+    Repeat { times: Expr, action: Ref },
+    Fire(Ref),
+    ChangeSpeed { speed: Value, term: Expr },
+    ChangeDirection { direction: Value, term: Expr },
+    Accel { horizontal: Option<Value>, vertical: Option<Value>, term: Expr },
+    Wait(Expr),
+    Vanish,
+    Action(Ref),
+}
+
+#[derive(Debug, Clone, Default)]
+struct Fire {
+    direction: Option<Value>,
+    speed: Option<Value>,
+    bullet: Ref,
+}
+
+#[derive(Debug, Clone, Default)]
+struct BulletSpec {
+    direction: Option<Value>,
+    speed: Option<Value>,
+    actions: Vec<Ref>,
+}
+
+/// Parsed BulletML document, the actions labelled `top*` run on the firing enemy
+#[derive(Debug, Default)]
+pub struct BulletMl {
+    top: Vec<usize>,
+    actions: Vec<Vec<Step>>,
+    fires: Vec<Fire>,
+    bullets: Vec<BulletSpec>,
+}
+
+#[derive(Debug)]
+pub enum BulletMlError {
+    Xml(roxmltree::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for BulletMlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BulletMlError::Xml(err) => write!(f, "{}", err),
+            BulletMlError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for BulletMlError {}
+
+fn invalid<T>(msg: String) -> Result<T, BulletMlError> {
+    Err(BulletMlError::Invalid(msg))
+}
+// endregion: Document
+
+// region:    Parser
+impl BulletMl {
+    pub fn parse(xml: &str) -> Result<Self, BulletMlError> {
+        let doc = roxmltree::Document::parse(xml).map_err(BulletMlError::Xml)?;
+        let root = doc.root_element();
+        if root.tag_name().name() != "bulletml" {
+            return invalid(format!("root element is <{}>, not <bulletml>", root.tag_name().name()));
+        }
+        if root.attribute("type") == Some("horizontal") {
+            return invalid("horizontal BulletML is not supported, the game scrolls vertically".to_string());
+        }
+
+        let mut builder = Builder::default();
+
+        // labelled elements get their slot first, so references can point forward
+        for node in root.descendants().filter(|node| node.is_element()) {
+            if let Some(label) = node.attribute("label") {
+                let ml = &mut builder.ml;
+                let (labels, index) = match node.tag_name().name() {
+                    "action" => {
+                        ml.actions.push(Vec::new());
+                        (&mut builder.action_labels, ml.actions.len() - 1)
+                    }
+                    "fire" => {
+                        ml.fires.push(Fire::default());
+                        (&mut builder.fire_labels, ml.fires.len() - 1)
+                    }
+                    "bullet" => {
+                        ml.bullets.push(BulletSpec::default());
+                        (&mut builder.bullet_labels, ml.bullets.len() - 1)
+                    }
+                    _ => continue,
+                };
+                if labels.insert(label.to_string(), index).is_some() {
+                    return invalid(format!("label '{}' is used twice", label));
+                }
+            }
+        }
+        for node in root.descendants().filter(|node| node.is_element()) {
+            if let Some(label) = node.attribute("label") {
+                match node.tag_name().name() {
+                    "action" => {
+                        let index = builder.action_labels[label];
+                        builder.ml.actions[index] = builder.steps(node)?;
+                        if label.starts_with("top") {
+                            builder.ml.top.push(index);
+                        }
+                    }
+                    "fire" => {
+                        let index = builder.fire_labels[label];
+                        builder.ml.fires[index] = builder.fire(node)?;
+                    }
+                    "bullet" => {
+                        let index = builder.bullet_labels[label];
+                        builder.ml.bullets[index] = builder.bullet(node)?;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if builder.ml.top.is_empty() {
+            return invalid("no action labelled 'top'".to_string());
+        }
+        Ok(builder.ml)
+    }
+}
+
+#[derive(Default)]
+struct Builder {
+    ml: BulletMl,
+    action_labels: HashMap<String, usize>,
+    fire_labels: HashMap<String, usize>,
+    bullet_labels: HashMap<String, usize>,
+}
+
+type Node<'a, 'input> = roxmltree::Node<'a, 'input>;
+
+fn children<'a, 'input: 'a>(node: Node<'a, 'input>) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children().filter(|child| child.is_element())
+}
+
+fn child<'a, 'input: 'a>(node: Node<'a, 'input>, names: &[&str]) -> Option<Node<'a, 'input>> {
+    children(node).find(|child| names.contains(&child.tag_name().name()))
+}
+
+fn required<'a, 'input: 'a>(node: Node<'a, 'input>, names: &[&str]) -> Result<Node<'a, 'input>, BulletMlError> {
+    child(node, names).map_or_else(
+        || invalid(format!("<{}> needs a <{}>", node.tag_name().name(), names.join("> or <"))),
+        Ok,
+    )
+}
+
+fn expr(node: Node) -> Result<Expr, BulletMlError> {
+    parse_expr(node.text().unwrap_or(""))
+}
+
+fn value(node: Node, default: Kind) -> Result<Value, BulletMlError> {
+    let kind = match node.attribute("type") {
+        None => default,
+        Some("aim") => Kind::Aim,
+        Some("absolute") => Kind::Absolute,
+        Some("relative") => Kind::Relative,
+        Some("sequence") => Kind::Sequence,
+        Some(other) => return invalid(format!("unknown <{}> type '{}'", node.tag_name().name(), other)),
+    };
+    Ok(Value { kind, expr: expr(node)? })
+}
+
+impl Builder {
+    fn steps(&mut self, node: Node) -> Result<Vec<Step>, BulletMlError> {
+        let mut steps = Vec::new();
+        for node in children(node) {
+            let step = match node.tag_name().name() {
+                "repeat" => Step::Repeat {
+                    times: expr(required(node, &["times"])?)?,
+                    action: self.action_ref(required(node, &["action", "actionRef"])?)?,
+                },
+                "fire" | "fireRef" => Step::Fire(self.fire_ref(node)?),
+                "changeSpeed" => Step::ChangeSpeed {
+                    speed: value(required(node, &["speed"])?, Kind::Absolute)?,
+                    term: expr(required(node, &["term"])?)?,
+                },
+                "changeDirection" => Step::ChangeDirection {
+                    direction: value(required(node, &["direction"])?, Kind::Aim)?,
+                    term: expr(required(node, &["term"])?)?,
+                },
+                "accel" => Step::Accel {
+                    horizontal: child(node, &["horizontal"]).map(|n| value(n, Kind::Absolute)).transpose()?,
+                    vertical: child(node, &["vertical"]).map(|n| value(n, Kind::Absolute)).transpose()?,
+                    term: expr(required(node, &["term"])?)?,
+                },
+                "wait" => Step::Wait(expr(node)?),
+                "vanish" => Step::Vanish,
+                "action" | "actionRef" => Step::Action(self.action_ref(node)?),
+                other => return invalid(format!("unexpected <{}> in <action>", other)),
+            };
+            steps.push(step);
+        }
+        Ok(steps)
+    }
+
+    fn fire(&mut self, node: Node) -> Result<Fire, BulletMlError> {
+        Ok(Fire {
+            direction: child(node, &["direction"]).map(|n| value(n, Kind::Aim)).transpose()?,
+            speed: child(node, &["speed"]).map(|n| value(n, Kind::Absolute)).transpose()?,
+            bullet: self.bullet_ref(required(node, &["bullet", "bulletRef"])?)?,
+        })
+    }
+
+    fn bullet(&mut self, node: Node) -> Result<BulletSpec, BulletMlError> {
+        let mut actions = Vec::new();
+        for action in children(node).filter(|n| matches!(n.tag_name().name(), "action" | "actionRef")) {
+            actions.push(self.action_ref(action)?);
+        }
+        Ok(BulletSpec {
+            direction: child(node, &["direction"]).map(|n| value(n, Kind::Aim)).transpose()?,
+            speed: child(node, &["speed"]).map(|n| value(n, Kind::Absolute)).transpose()?,
+            actions,
+        })
+    }
+
+    fn action_ref(&mut self, node: Node) -> Result<Ref, BulletMlError> {
+        if node.tag_name().name() == "action" {
+            let index = match node.attribute("label") {
+                Some(label) => self.action_labels[label],
+                None => {
+                    let steps = self.steps(node)?;
+                    self.ml.actions.push(steps);
+                    self.ml.actions.len() - 1
+                }
+            };
+            return Ok(Ref { index, params: None });
+        }
+        labelled(&self.action_labels, node)
+    }
+
+    fn fire_ref(&mut self, node: Node) -> Result<Ref, BulletMlError> {
+        if node.tag_name().name() == "fire" {
+            let index = match node.attribute("label") {
+                Some(label) => self.fire_labels[label],
+                None => {
+                    let fire = self.fire(node)?;
+                    self.ml.fires.push(fire);
+                    self.ml.fires.len() - 1
+                }
+            };
+            return Ok(Ref { index, params: None });
+        }
+        labelled(&self.fire_labels, node)
+    }
+
+    fn bullet_ref(&mut self, node: Node) -> Result<Ref, BulletMlError> {
+        if node.tag_name().name() == "bullet" {
+            let index = match node.attribute("label") {
+                Some(label) => self.bullet_labels[label],
+                None => {
+                    let bullet = self.bullet(node)?;
+                    self.ml.bullets.push(bullet);
+                    self.ml.bullets.len() - 1
+                }
+            };
+            return Ok(Ref { index, params: None });
+        }
+        labelled(&self.bullet_labels, node)
+    }
+}
+
+/// `actionRef`, `fireRef` and `bulletRef`, with their `param` values
+fn labelled(labels: &HashMap<String, usize>, node: Node) -> Result<Ref, BulletMlError> {
+    let label = match node.attribute("label") {
+        Some(label) => label,
+        None => return invalid(format!("<{}> without a label", node.tag_name().name())),
+    };
+    let index = match labels.get(label) {
+        Some(index) => *index,
+        None => return invalid(format!("<{}> to unknown label '{}'", node.tag_name().name(), label)),
+    };
+    let params = children(node)
+        .filter(|n| n.tag_name().name() == "param")
+        .map(expr)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Ref { index, params: Some(params) })
+}
+// endregion: Parser
+
+// region:    Expressions
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(f32),
+    Rand,
+    Rank,
+    Param(usize),
+    Op(char),
+    Open,
+    Close,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, BulletMlError> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '0'..='9' | '.' => {
+                let mut number = String::new();
+                while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit() || **c == '.') {
+                    number.push(c);
+                    chars.next();
+                }
+                match number.parse() {
+                    Ok(n) => tokens.push(Token::Num(n)),
+                    Err(_) => return invalid(format!("bad number '{}' in '{}'", number, text)),
+                }
+            }
+            '$' => {
+                chars.next();
+                let mut name = String::new();
+                while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphanumeric()) {
+                    name.push(c);
+                    chars.next();
+                }
+                let token = match name.as_str() {
+                    "rand" => Token::Rand,
+                    "rank" => Token::Rank,
+                    _ => match name.parse::<usize>() {
+                        Ok(n) if n > 0 => Token::Param(n - 1),
+                        _ => return invalid(format!("unknown variable '${}' in '{}'", name, text)),
+                    },
+                };
+                tokens.push(token);
+            }
+            '+' | '-' | '*' | '/' | '%' => {
+                tokens.push(Token::Op(c));
+                chars.next();
+            }
+            '(' => {
+                tokens.push(Token::Open);
+                chars.next();
+            }
+            ')' => {
+                tokens.push(Token::Close);
+                chars.next();
+            }
+            _ => return invalid(format!("unexpected '{}' in '{}'", c, text)),
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_expr(text: &str) -> Result<Expr, BulletMlError> {
+    let mut parser = ExprParser {
+        tokens: tokenize(text)?,
+        pos: 0,
+    };
+    let expr = parser.sum()?;
+    if parser.pos != parser.tokens.len() {
+        return invalid(format!("unexpected tokens at the end of '{}'", text));
+    }
+    Ok(expr)
+}
+
+struct ExprParser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl ExprParser {
+    fn next_op(&mut self, ops: &[char]) -> Option<char> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) if ops.contains(op) => {
+                self.pos += 1;
+                Some(*op)
+            }
+            _ => None,
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expr, BulletMlError> {
+        let mut expr = self.product()?;
+        while let Some(op) = self.next_op(&['+', '-']) {
+            let op = if op == '+' { Op::Add } else { Op::Sub };
+            expr = Expr::Bin(op, Box::new(expr), Box::new(self.product()?));
+        }
+        Ok(expr)
+    }
+
+    fn product(&mut self) -> Result<Expr, BulletMlError> {
+        let mut expr = self.unary()?;
+        while let Some(op) = self.next_op(&['*', '/', '%']) {
+            let op = match op {
+                '*' => Op::Mul,
+                '/' => Op::Div,
+                _ => Op::Rem,
+            };
+            expr = Expr::Bin(op, Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, BulletMlError> {
+        match self.next_op(&['+', '-']) {
+            Some('-') => Ok(Expr::Neg(Box::new(self.unary()?))),
+            Some(_) => self.unary(),
+            None => self.atom(),
+        }
+    }
+
+    fn atom(&mut self) -> Result<Expr, BulletMlError> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Num(n)) => Ok(Expr::Num(n)),
+            Some(Token::Rand) => Ok(Expr::Rand),
+            Some(Token::Rank) => Ok(Expr::Rank),
+            Some(Token::Param(n)) => Ok(Expr::Param(n)),
+            Some(Token::Open) => {
+                let expr = self.sum()?;
+                if self.tokens.get(self.pos) != Some(&Token::Close) {
+                    return invalid("missing ')'".to_string());
+                }
+                self.pos += 1;
+                Ok(expr)
+            }
+            _ => invalid("expected a number, a variable or '('".to_string()),
+        }
+    }
+}
+
+struct Eval<'a> {
+    params: &'a [f32],
+    rank: f32,
+    rng: &'a mut dyn RngCore,
+}
+
+impl Expr {
+    fn eval(&self, ctx: &mut Eval) -> f32 {
+        match self {
+            Expr::Num(n) => *n,
+            Expr::Rand => ctx.rng.gen::<f32>(),
+            Expr::Rank => ctx.rank,
+            // missing parameters count as 0, like in the reference implementation
+            Expr::Param(n) => ctx.params.get(*n).copied().unwrap_or(0.),
+            Expr::Neg(expr) => -expr.eval(ctx),
+            Expr::Bin(op, a, b) => {
+                let (a, b) = (a.eval(ctx), b.eval(ctx));
+                match op {
+                    Op::Add => a + b,
+                    Op::Sub => a - b,
+                    Op::Mul => a * b,
+                    Op::Div if b == 0. => 0.,
+                    Op::Div => a / b,
+                    Op::Rem if b == 0. => 0.,
+                    Op::Rem => a % b,
+                }
+            }
+        }
+    }
+}
+// endregion: Expressions
+
+// region:    Runner
+struct Frame {
+    action: usize,
+    pc: usize,
+    params: Vec<f32>,
+    repeat: u32,
+}
+
+struct Thread {
+    frames: Vec<Frame>,
+    wait: u32,
+    last_direction: Option<f32>,
+    last_speed: f32,
+}
+
+impl Thread {
+    fn new(action: usize, params: Vec<f32>) -> Self {
+        Self {
+            frames: vec![Frame { action, pc: 0, params, repeat: 0 }],
+            wait: 0,
+            last_direction: None,
+            last_speed: 1.,
+        }
+    }
+
+    /// Enter a nested action, a thread nesting deeper than MAX_ACTION_DEPTH ends
+    fn call(&mut self, frame: Frame) {
+        if self.frames.len() >= MAX_ACTION_DEPTH {
+            self.frames.clear();
+        } else {
+            self.frames.push(frame);
+        }
+    }
+}
+
+/// Gradual change over a number of frames
+#[derive(Clone, Copy)]
+struct Change {
+    per_frame: f32,
+    frames: u32,
+}
+
+impl Change {
+    fn apply(change: &mut Option<Change>, value: &mut f32) {
+        if let Some(c) = change {
+            *value += c.per_frame;
+            c.frames -= 1;
+            if c.frames == 0 {
+                *change = None;
+            }
+        }
+    }
+}
+
+/// Bullet to fire, with the actions it runs on its own
+struct Shot {
+    direction: f32,
+    speed: f32,
+    threads: Vec<Thread>,
+}
+
+/// BulletML actions running on an enemy or on one of its bullets
+#[derive(Component)]
+pub struct BulletMlRunner {
+    doc: Arc<BulletMl>,
+    threads: Vec<Thread>,
+    direction: f32,
+    speed: f32,
+    accel: Vec2,
+    direction_change: Option<Change>,
+    speed_change: Option<Change>,
+    accel_change: [Option<Change>; 2],
+    vanished: bool,
+}
+
+impl BulletMlRunner {
+    /// Runner for the `top` actions of an enemy, started over each time they are done
+    pub fn new(doc: Arc<BulletMl>) -> Self {
+        let mut runner = Self::with_threads(doc, Vec::new(), 180., 0.);
+        runner.restart();
+        runner
+    }
+
+    fn with_threads(doc: Arc<BulletMl>, threads: Vec<Thread>, direction: f32, speed: f32) -> Self {
+        Self {
+            doc,
+            threads,
+            direction,
+            speed,
+            accel: Vec2::ZERO,
+            direction_change: None,
+            speed_change: None,
+            accel_change: [None, None],
+            vanished: false,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.threads = self.doc.top.iter().map(|top| Thread::new(*top, Vec::new())).collect();
+    }
+
+    fn is_done(&self) -> bool {
+        self.threads.iter().all(|thread| thread.frames.is_empty())
+    }
+
+    /// No direction, speed or acceleration change in progress
+    fn is_settled(&self) -> bool {
+        self.direction_change.is_none()
+            && self.speed_change.is_none()
+            && self.accel_change.iter().all(Option::is_none)
+    }
+
+    /// Run one frame, `aim` is the direction of the player from the mover
+    fn tick(&mut self, aim: f32, rank: f32, rng: &mut dyn RngCore) -> Vec<Shot> {
+        Change::apply(&mut self.direction_change, &mut self.direction);
+        Change::apply(&mut self.speed_change, &mut self.speed);
+        Change::apply(&mut self.accel_change[0], &mut self.accel.x);
+        Change::apply(&mut self.accel_change[1], &mut self.accel.y);
+
+        let mut shots = Vec::new();
+        let mut threads = std::mem::take(&mut self.threads);
+        for thread in threads.iter_mut() {
+            self.run(thread, aim, rank, rng, &mut shots);
+        }
+        self.threads = threads;
+        shots
+    }
+
+    fn run(&mut self, thread: &mut Thread, aim: f32, rank: f32, rng: &mut dyn RngCore, shots: &mut Vec<Shot>) {
+        if thread.wait > 0 {
+            thread.wait -= 1;
+            return;
+        }
+        let doc = self.doc.clone();
+        let mut budget = MAX_STEPS_PER_TICK;
+        while let Some(frame) = thread.frames.last_mut() {
+            if budget == 0 || self.vanished {
+                return;
+            }
+            budget -= 1;
+
+            let steps = &doc.actions[frame.action];
+            if frame.pc >= steps.len() {
+                if frame.repeat > 0 {
+                    frame.repeat -= 1;
+                    frame.pc = 0;
+                } else {
+                    thread.frames.pop();
+                }
+                continue;
+            }
+            let step = &steps[frame.pc];
+            frame.pc += 1;
+
+            let params = frame.params.clone();
+            let mut ctx = Eval { params: &params, rank, rng: &mut *rng };
+            match step {
+                Step::Repeat { times, action } => {
+                    let times = times.eval(&mut ctx).floor();
+                    if times >= 1. {
+                        let params = ref_params(action, &mut ctx);
+                        thread.call(Frame {
+                            action: action.index,
+                            pc: 0,
+                            params,
+                            repeat: times as u32 - 1,
+                        });
+                    }
+                }
+                Step::Action(action) => {
+                    let params = ref_params(action, &mut ctx);
+                    thread.call(Frame { action: action.index, pc: 0, params, repeat: 0 });
+                }
+                Step::Fire(fire_ref) => {
+                    let fire = &doc.fires[fire_ref.index];
+                    let bullet = &doc.bullets[fire.bullet.index];
+                    let fire_params = ref_params(fire_ref, &mut ctx);
+                    let mut fire_ctx = Eval { params: &fire_params, rank, rng: &mut *rng };
+                    let bullet_params = ref_params(&fire.bullet, &mut fire_ctx);
+
+                    // the fire settings win over the bullet ones
+                    let direction = match (&fire.direction, &bullet.direction) {
+                        (Some(direction), _) => direction.eval(&mut fire_ctx),
+                        (None, Some(direction)) => {
+                            direction.eval(&mut Eval { params: &bullet_params, rank, rng: &mut *rng })
+                        }
+                        (None, None) => (Kind::Aim, 0.),
+                    };
+                    let speed = match (&fire.speed, &bullet.speed) {
+                        (Some(speed), _) => speed.eval(&mut Eval { params: &fire_params, rank, rng: &mut *rng }),
+                        (None, Some(speed)) => speed.eval(&mut Eval { params: &bullet_params, rank, rng: &mut *rng }),
+                        (None, None) => (Kind::Absolute, 1.),
+                    };
+
+                    let direction = match direction {
+                        (Kind::Absolute, v) => v,
+                        (Kind::Relative, v) => self.direction + v,
+                        (Kind::Sequence, v) => thread.last_direction.unwrap_or(aim) + v,
+                        (Kind::Aim, v) => aim + v,
+                    };
+                    let speed = match speed {
+                        (Kind::Relative, v) => self.speed + v,
+                        (Kind::Sequence, v) => thread.last_speed + v,
+                        (_, v) => v,
+                    };
+                    thread.last_direction = Some(direction);
+                    thread.last_speed = speed;
+
+                    let mut bullet_ctx = Eval { params: &bullet_params, rank, rng: &mut *rng };
+                    let threads = bullet
+                        .actions
+                        .iter()
+                        .map(|action| Thread::new(action.index, ref_params(action, &mut bullet_ctx)))
+                        .collect();
+                    shots.push(Shot { direction, speed, threads });
+                }
+                Step::ChangeSpeed { speed, term } => {
+                    let frames = term.eval(&mut ctx).max(1.) as u32;
+                    let v = speed.expr.eval(&mut ctx);
+                    let per_frame = match speed.kind {
+                        Kind::Sequence => v,
+                        Kind::Relative => v / frames as f32,
+                        _ => (v - self.speed) / frames as f32,
+                    };
+                    self.speed_change = Some(Change { per_frame, frames });
+                }
+                Step::ChangeDirection { direction, term } => {
+                    let frames = term.eval(&mut ctx).max(1.) as u32;
+                    let v = direction.expr.eval(&mut ctx);
+                    let per_frame = match direction.kind {
+                        Kind::Sequence => v,
+                        Kind::Aim => shortest_turn(aim + v - self.direction) / frames as f32,
+                        Kind::Absolute => shortest_turn(v - self.direction) / frames as f32,
+                        Kind::Relative => shortest_turn(v) / frames as f32,
+                    };
+                    self.direction_change = Some(Change { per_frame, frames });
+                }
+                Step::Accel { horizontal, vertical, term } => {
+                    let frames = term.eval(&mut ctx).max(1.) as u32;
+                    let current = [self.accel.x, self.accel.y];
+                    for (i, value) in [horizontal, vertical].into_iter().enumerate() {
+                        if let Some(value) = value {
+                            let v = value.expr.eval(&mut ctx);
+                            let per_frame = match value.kind {
+                                Kind::Sequence => v,
+                                Kind::Relative => v / frames as f32,
+                                _ => (v - current[i]) / frames as f32,
+                            };
+                            self.accel_change[i] = Some(Change { per_frame, frames });
+                        }
+                    }
+                }
+                Step::Wait(frames) => {
+                    let frames = frames.eval(&mut ctx).floor();
+                    if frames >= 1. {
+                        thread.wait = frames as u32 - 1;
+                        return;
+                    }
+                }
+                Step::Vanish => self.vanished = true,
+            }
+        }
+    }
+}
+
+impl Value {
+    fn eval(&self, ctx: &mut Eval) -> (Kind, f32) {
+        (self.kind, self.expr.eval(ctx))
+    }
+}
+
+fn ref_params(reference: &Ref, ctx: &mut Eval) -> Vec<f32> {
+    match &reference.params {
+        Some(params) => params.iter().map(|param| param.eval(ctx)).collect(),
+        None => ctx.params.to_vec(),
+    }
+}
+
+/// Same turn in degrees, taking the short way round
+fn shortest_turn(degrees: f32) -> f32 {
+    let turn = degrees.rem_euclid(360.);
+    if turn > 180. {
+        turn - 360.
+    } else {
+        turn
+    }
+}
+
+/// BulletML direction in degrees to a `Bullet` angle in radians, 0 pointing down
+fn bullet_angle(direction: f32) -> f32 {
+    (180. - direction).to_radians()
+}
+
+/// BulletML direction from `from` towards `target`
+fn aim_direction(from: Vec3, target: Vec3) -> f32 {
+    let aim = target - from;
+    aim.x.atan2(aim.y).to_degrees()
+}
+// endregion: Runner
+
+impl Plugin for BulletMlPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set_to_stage(FIXED_UPDATE, simulation(SimulationStep::BulletMl).with_system(bulletml_run.system()));
+    }
+}
+
+fn bulletml_run(
+    mut commands: Commands,
+    textures: Res<SpriteInfos>,
+    wave_director: Res<WaveDirector>,
+    mut game_rng: ResMut<GameRng>,
+    player_query: Query<&Transform, With<Player>>,
+    mut query: Query<(Entity, &mut BulletMlRunner, &Transform, Option<&mut Bullet>)>,
+) {
+    let player_position = player_query.iter().next().map(|tf| tf.translation);
+    let rank = wave_director.rank();
+    // BulletML speeds are per frame, and a frame is one tick
+    let frame_rate = 1. / TIME_STEP;
+
+    for (entity, mut runner, tf, bullet) in query.iter_mut() {
+        let position = Vec3::new(tf.translation.x, tf.translation.y, 0.);
+        let aim = match player_position {
+            Some(target) => aim_direction(position, target),
+            None => 180.,
+        };
+        let shots = runner.tick(aim, rank, &mut game_rng.rng);
+
+        for shot in shots {
+            let def = BulletDef {
+                speed: shot.speed * frame_rate,
+                acceleration: 0.,
+                angular_velocity: 0.,
+            };
+            let child = spawn_bullet(&mut commands, &textures, position, bullet_angle(shot.direction), &def);
+            if !shot.threads.is_empty() {
+                let doc = runner.doc.clone();
+                commands
+                    .entity(child)
+                    .insert(BulletMlRunner::with_threads(doc, shot.threads, shot.direction, shot.speed));
+            }
+        }
+
+        match bullet {
+            // scripted bullets follow their runner, then keep going once it is done
+            Some(mut bullet) => {
+                if runner.vanished {
+                    commands.entity(entity).despawn();
+                    continue;
+                }
+                // BulletML vertical speeds grow downwards
+                let drift = Vec2::new(runner.accel.x, -runner.accel.y) * frame_rate;
+                bullet.set_motion(bullet_angle(runner.direction), runner.speed * frame_rate, drift);
+                if runner.is_done() && runner.is_settled() {
+                    commands.entity(entity).remove::<BulletMlRunner>();
+                }
+            }
+            None => {
+                if runner.is_done() || runner.vanished {
+                    runner.vanished = false;
+                    runner.restart();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    fn doc(body: &str) -> Result<BulletMl, BulletMlError> {
+        BulletMl::parse(&format!("<bulletml type=\"vertical\">{}</bulletml>", body))
+    }
+
+    fn eval(text: &str, params: &[f32], rank: f32) -> f32 {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        parse_expr(text).unwrap().eval(&mut Eval { params, rank, rng: &mut rng })
+    }
+
+    fn invalid_message(result: Result<BulletMl, BulletMlError>) -> String {
+        match result {
+            Err(BulletMlError::Invalid(msg)) => msg,
+            Err(err) => panic!("expected an invalid document, got {}", err),
+            Ok(_) => panic!("expected an invalid document, it parsed"),
+        }
+    }
+
+    /// Tick at which each shot is fired and its direction, over `ticks` ticks
+    fn run(ml: BulletMl, ticks: u32, aim: f32) -> (Vec<(u32, f32)>, BulletMlRunner) {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let mut runner = BulletMlRunner::new(Arc::new(ml));
+        let mut fired = Vec::new();
+        for tick in 0..ticks {
+            for shot in runner.tick(aim, 0., &mut rng) {
+                fired.push((tick, shot.direction));
+            }
+        }
+        (fired, runner)
+    }
+
+    #[test]
+    fn bundled_scripts_parse() {
+        for xml in [
+            include_str!("../assets/bulletml/homing_spiral.xml"),
+            include_str!("../assets/bulletml/sniper_burst.xml"),
+        ] {
+            let ml = BulletMl::parse(xml).unwrap();
+            assert_eq!(ml.top.len(), 1);
+        }
+    }
+
+    #[test]
+    fn references_can_point_forward() {
+        let ml = doc(r#"
+            <action label="top"><actionRef label="later"/></action>
+            <action label="later"><fireRef label="shot"/></action>
+            <fire label="shot"><bulletRef label="plain"/></fire>
+            <bullet label="plain"/>
+        "#)
+        .unwrap();
+        let (fired, _) = run(ml, 1, 0.);
+        assert_eq!(fired.len(), 1);
+    }
+
+    #[test]
+    fn operator_precedence() {
+        assert_eq!(eval("1 + 2 * 3", &[], 0.), 7.);
+        assert_eq!(eval("(1 + 2) * 3", &[], 0.), 9.);
+        assert_eq!(eval("10 - 4 - 3", &[], 0.), 3.);
+        assert_eq!(eval("8 / 2 / 2", &[], 0.), 2.);
+        assert_eq!(eval("-2 * 3 + 10 % 4", &[], 0.), -4.);
+        assert_eq!(eval("2 * -(1 + 1)", &[], 0.), -4.);
+        assert_eq!(eval("1 / 0", &[], 0.), 0.);
+    }
+
+    #[test]
+    fn variables() {
+        assert_eq!(eval("$1 * 2 + $2", &[3., 4.], 0.), 10.);
+        // missing parameters are 0
+        assert_eq!(eval("$3 + 1", &[3., 4.], 0.), 1.);
+        assert_eq!(eval("4 + $rank * 2", &[], 0.5), 5.);
+        for _ in 0..10 {
+            let value = eval("$rand", &[], 0.);
+            assert!((0. ..1.).contains(&value));
+        }
+        assert!(parse_expr("$0").is_err());
+        assert!(parse_expr("$speed").is_err());
+        assert!(parse_expr("(1 + 2").is_err());
+        assert!(parse_expr("1 2").is_err());
+    }
+
+    #[test]
+    fn repeat_and_wait_count_frames() {
+        let script = r#"
+            <action label="top">
+              <repeat>
+                <times>3</times>
+                <action><fire><bullet/></fire><wait>5</wait></action>
+              </repeat>
+            </action>
+        "#;
+        let (fired, runner) = run(doc(script).unwrap(), 15, 0.);
+        let ticks: Vec<u32> = fired.iter().map(|(tick, _)| *tick).collect();
+        assert_eq!(ticks, vec![0, 5, 10]);
+        // the last wait is still running
+        assert!(!runner.is_done());
+
+        let (_, runner) = run(doc(script).unwrap(), 16, 0.);
+        assert!(runner.is_done());
+    }
+
+    #[test]
+    fn params_reach_referenced_actions() {
+        let ml = doc(r#"
+            <action label="top">
+              <actionRef label="volley"><param>2 + 1</param></actionRef>
+            </action>
+            <action label="volley">
+              <repeat><times>$1</times><action><fire><bullet/></fire></action></repeat>
+            </action>
+        "#)
+        .unwrap();
+        let (fired, _) = run(ml, 1, 0.);
+        assert_eq!(fired.len(), 3);
+    }
+
+    #[test]
+    fn aimed_and_sequence_directions() {
+        let ml = doc(r#"
+            <action label="top">
+              <fire><direction type="aim">10</direction><bullet/></fire>
+              <repeat>
+                <times>2</times>
+                <action><fire><direction type="sequence">15</direction><bullet/></fire></action>
+              </repeat>
+              <fire><direction type="absolute">45</direction><bullet/></fire>
+              <fire><bullet/></fire>
+            </action>
+        "#)
+        .unwrap();
+        let (fired, _) = run(ml, 1, 90.);
+        let directions: Vec<f32> = fired.iter().map(|(_, direction)| *direction).collect();
+        assert_eq!(directions, vec![100., 115., 130., 45., 90.]);
+    }
+
+    #[test]
+    fn sequence_starts_from_the_aim() {
+        let ml = doc(r#"
+            <action label="top">
+              <fire><direction type="sequence">20</direction><bullet/></fire>
+            </action>
+        "#)
+        .unwrap();
+        let (fired, _) = run(ml, 1, -30.);
+        assert_eq!(fired[0].1, -10.);
+    }
+
+    #[test]
+    fn recursive_actions_end_the_thread() {
+        let ml = doc(r#"
+            <action label="top"><fire><bullet/></fire><actionRef label="top"/></action>
+        "#)
+        .unwrap();
+        let (fired, runner) = run(ml, 2, 0.);
+        assert_eq!(fired.len(), MAX_ACTION_DEPTH);
+        assert!(runner.is_done());
+    }
+
+    #[test]
+    fn duplicate_label() {
+        let msg = invalid_message(doc(r#"
+            <action label="top"/>
+            <action label="twice"/>
+            <action label="twice"/>
+        "#));
+        assert!(msg.contains("'twice' is used twice"), "{}", msg);
+    }
+
+    #[test]
+    fn unknown_reference() {
+        let msg = invalid_message(doc(r#"<action label="top"><actionRef label="nowhere"/></action>"#));
+        assert!(msg.contains("unknown label 'nowhere'"), "{}", msg);
+    }
+
+    #[test]
+    fn missing_top() {
+        let msg = invalid_message(doc(r#"<action label="start"><wait>1</wait></action>"#));
+        assert!(msg.contains("no action labelled 'top'"), "{}", msg);
+    }
+}
@@ -0,0 +1,209 @@
+use bevy::prelude::*;
+use bevy::render::render_resource::{Extent3d, FilterMode, TextureDimension, TextureFormat};
+use crate::defs::GameDefs;
+use crate::timestep::Interpolated;
+use crate::wave::WaveDirector;
+use crate::{
+    simulation, SimulationStep, AppState, FromEnemy, FromPlayer, GameMode, Laser, PlayField, SpriteInfos, SpriteSize, FIXED_UPDATE,
+};
+
+pub struct BunkerPlugin;
+
+// every bunker is a grid of cells, each drawn as a CELL_SIZE pixels square
+const BUNKER_COLUMNS: usize = 22;
+const BUNKER_ROWS: usize = 16;
+const CELL_SIZE: f32 = 4.;
+// cells this close to a hit, in cells, are blown away
+const EROSION_RADIUS: f32 = 2.5;
+const BUNKER_COLOR: [u8; 4] = [90, 220, 90, 255];
+// bunker centers above the bottom of the play field
+const BUNKER_HEIGHT: f32 = 130.;
+
+/// Destructible shield, lasers from both sides erode its cells
+#[derive(Component)]
+pub struct Bunker {
+    // row-major, top row first
+    cells: Vec<bool>,
+}
+
+impl Bunker {
+    /// The classic arch: top corners cut off and a notch at the bottom
+    fn intact() -> Self {
+        let cells = (0..BUNKER_ROWS)
+            .flat_map(|row| {
+                (0..BUNKER_COLUMNS).map(move |column| {
+                    let corner = 4usize.saturating_sub(row);
+                    let notch = row + 5 >= BUNKER_ROWS && (7..BUNKER_COLUMNS - 7).contains(&column);
+                    column >= corner && column < BUNKER_COLUMNS - corner && !notch
+                })
+            })
+            .collect();
+        Self { cells }
+    }
+
+    fn size() -> Vec2 {
+        Vec2::new(BUNKER_COLUMNS as f32, BUNKER_ROWS as f32) * CELL_SIZE
+    }
+
+    /// First intact cell a laser of `size` touches on its way from `from` to `to`,
+    /// both relative to the bunker center, so fast lasers cannot skip over thin walls
+    fn first_hit(&self, from: Vec2, to: Vec2, size: Vec2) -> Option<(usize, usize)> {
+        let steps = ((to - from).length() / (CELL_SIZE / 2.)).ceil().max(1.) as usize;
+        (0..=steps).find_map(|step| self.cell_in(from.lerp(to, step as f32 / steps as f32), size))
+    }
+
+    /// Intact cell covered by a rectangle of `size` around `center`, the closest one to the center
+    fn cell_in(&self, center: Vec2, size: Vec2) -> Option<(usize, usize)> {
+        let half = Self::size() / 2.;
+        // rectangle in cell coordinates, columns left to right and rows top to bottom
+        let min = Vec2::new(center.x - size.x / 2. + half.x, half.y - center.y - size.y / 2.) / CELL_SIZE;
+        let max = Vec2::new(center.x + size.x / 2. + half.x, half.y - center.y + size.y / 2.) / CELL_SIZE;
+        if max.x < 0. || max.y < 0. || min.x >= BUNKER_COLUMNS as f32 || min.y >= BUNKER_ROWS as f32 {
+            return None;
+        }
+        let columns = min.x.max(0.) as usize..=(max.x as usize).min(BUNKER_COLUMNS - 1);
+        let rows = min.y.max(0.) as usize..=(max.y as usize).min(BUNKER_ROWS - 1);
+        let middle = (min + max) / 2.;
+        rows.flat_map(|row| columns.clone().map(move |column| (column, row)))
+            .filter(|&(column, row)| self.cells[row * BUNKER_COLUMNS + column])
+            .min_by(|a, b| {
+                let distance = |(column, row): (usize, usize)| {
+                    (Vec2::new(column as f32, row as f32) + 0.5 - middle).length_squared()
+                };
+                distance(*a).total_cmp(&distance(*b))
+            })
+    }
+
+    fn erode(&mut self, (column, row): (usize, usize)) {
+        for (i, cell) in self.cells.iter_mut().enumerate() {
+            let dx = (i % BUNKER_COLUMNS) as f32 - column as f32;
+            let dy = (i / BUNKER_COLUMNS) as f32 - row as f32;
+            if dx * dx + dy * dy <= EROSION_RADIUS * EROSION_RADIUS {
+                *cell = false;
+            }
+        }
+    }
+
+    fn is_destroyed(&self) -> bool {
+        !self.cells.iter().any(|&cell| cell)
+    }
+
+    /// One RGBA pixel per cell, destroyed cells are transparent
+    fn pixels(&self) -> Vec<u8> {
+        self.cells
+            .iter()
+            .flat_map(|&cell| if cell { BUNKER_COLOR } else { [0; 4] })
+            .collect()
+    }
+
+    fn image(&self) -> Image {
+        let size = Extent3d {
+            width: BUNKER_COLUMNS as u32,
+            height: BUNKER_ROWS as u32,
+            depth_or_array_layers: 1,
+        };
+        let mut image = Image::new(size, TextureDimension::D2, self.pixels(), TextureFormat::Rgba8UnormSrgb);
+        // keep the cells sharp when scaled up
+        image.sampler_descriptor.mag_filter = FilterMode::Nearest;
+        image
+    }
+}
+
+// Resource, wave the bunkers were last built for
+#[derive(Default)]
+struct BunkersBuilt(u32);
+
+impl Plugin for BunkerPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(BunkersBuilt::default())
+            .add_system_set(SystemSet::on_enter(AppState::Playing).with_system(bunker_reset.system()))
+            .add_system_set_to_stage(
+                FIXED_UPDATE,
+                simulation(SimulationStep::Bunker)
+                    .with_system(bunker_build.system().label("bunker_build"))
+                    .with_system(bunker_erosion.system().after("bunker_build")),
+            );
+    }
+}
+
+fn bunker_reset(mut built: ResMut<BunkersBuilt>) {
+    *built = BunkersBuilt::default();
+}
+
+/// Fresh bunkers, evenly spread across the play field, at the start of every wave
+#[allow(clippy::too_many_arguments)]
+fn bunker_build(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    mut built: ResMut<BunkersBuilt>,
+    wave_director: Res<WaveDirector>,
+    field: Res<PlayField>,
+    mode: Res<GameMode>,
+    defs: Res<GameDefs>,
+    query: Query<Entity, With<Bunker>>,
+) {
+    let count = defs.script(*mode).bunkers;
+    if count == 0 || built.0 == wave_director.wave() {
+        return;
+    }
+    built.0 = wave_director.wave();
+
+    for entity in query.iter() {
+        commands.entity(entity).despawn();
+    }
+    let gap = field.w / count as f32;
+    let y = -field.h / 2. + BUNKER_HEIGHT;
+    for i in 0..count {
+        let x = -field.w / 2. + gap * (i as f32 + 0.5);
+        let bunker = Bunker::intact();
+        commands
+            .spawn_bundle(SpriteBundle {
+                texture: images.add(bunker.image()),
+                sprite: Sprite {
+                    custom_size: Some(Bunker::size()),
+                    ..Default::default()
+                },
+                transform: Transform::from_xyz(x, y, 5.),
+                ..Default::default()
+            })
+            .insert(bunker);
+    }
+}
+
+/// Lasers from either side blow a hole in the first bunker cell they touch,
+/// anywhere along the way they moved this tick
+#[allow(clippy::type_complexity)]
+fn bunker_erosion(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    sprite_infos: Res<SpriteInfos>,
+    laser_query: Query<
+        (Entity, &Transform, Option<&SpriteSize>, Option<&Interpolated>),
+        (With<Laser>, Or<(With<FromPlayer>, With<FromEnemy>)>),
+    >,
+    mut bunker_query: Query<(Entity, &Transform, &mut Bunker, &Handle<Image>)>,
+) {
+    for (laser_entity, laser_tf, laser_size, interpolated) in laser_query.iter() {
+        // player lasers carry their size, enemy lasers all share one sprite
+        let laser_size = laser_size.map_or(sprite_infos.enemy_laser.1, |size| size.0) * laser_tf.scale.abs().truncate();
+        // lasers spawned this tick start where they are
+        let previous = interpolated.map_or(laser_tf.translation, |interpolated| interpolated.previous());
+        for (bunker_entity, bunker_tf, mut bunker, texture) in bunker_query.iter_mut() {
+            let from = (previous - bunker_tf.translation).truncate();
+            let to = (laser_tf.translation - bunker_tf.translation).truncate();
+            let cell = match bunker.first_hit(from, to, laser_size) {
+                Some(cell) => cell,
+                None => continue,
+            };
+            commands.entity(laser_entity).despawn();
+            bunker.erode(cell);
+            if bunker.is_destroyed() {
+                commands.entity(bunker_entity).despawn();
+            } else if let Some(image) = images.get_mut(texture) {
+                image.data = bunker.pixels();
+            }
+            break;
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::path::PathBuf;
+use crate::{GameMode, WINDOW_HEIGHT, WINDOW_WIDTH};
+
+const DEFAULT_REPLAY: &str = "replays/last_run.ron";
+// formations are placed at least 100 pixels inside the top and bottom edges
+const MIN_WIDTH: f32 = 320.;
+const MIN_HEIGHT: f32 = 240.;
+
+const USAGE: &str = "usage: bullet_hell [--headless <ticks>] [--size <width>x<height>] [--field <width>x<height>]\n       [--classic] [--seed <u64>] [--record <replay.ron> | --replay <replay.ron>]";
+
+pub struct Options {
+    /// run the simulation without a window for this many ticks
+    pub headless: Option<u32>,
+    pub width: f32,
+    pub height: f32,
+    /// area the player can move in, centered in the window, the whole window when not given
+    pub field: Option<(f32, f32)>,
+    /// classic rules instead of formation waves
+    pub mode: GameMode,
+    /// gameplay rng seed, random when not given
+    pub seed: Option<u64>,
+    /// where the run is recorded to
+    pub record: PathBuf,
+    /// replay file to play back instead of reading the keyboard, in the seed, mode and sizes it was recorded with
+    pub replay: Option<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            headless: None,
+            width: WINDOW_WIDTH,
+            height: WINDOW_HEIGHT,
+            field: None,
+            mode: GameMode::Formations,
+            seed: None,
+            record: PathBuf::from(DEFAULT_REPLAY),
+            replay: None,
+        }
+    }
+}
+
+impl Options {
+    pub fn from_args() -> Result<Self, String> {
+        let mut options = Options::default();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| format!("{} expects a value\n{}", name, USAGE))
+            };
+            match arg.as_str() {
+                "--headless" => {
+                    let ticks = value("--headless")?;
+                    let ticks = ticks
+                        .parse()
+                        .map_err(|_| format!("invalid tick count '{}'\n{}", ticks, USAGE))?;
+                    options.headless = Some(ticks);
+                }
+                "--size" => {
+                    let (width, height) = parse_size(&value("--size")?)?;
+                    options.width = width;
+                    options.height = height;
+                }
+                "--field" => options.field = Some(parse_size(&value("--field")?)?),
+                "--classic" => options.mode = GameMode::Classic,
+                "--seed" => {
+                    let seed = value("--seed")?;
+                    let seed = seed
+                        .parse()
+                        .map_err(|_| format!("invalid seed '{}'\n{}", seed, USAGE))?;
+                    options.seed = Some(seed);
+                }
+                "--record" => options.record = PathBuf::from(value("--record")?),
+                "--replay" => options.replay = Some(PathBuf::from(value("--replay")?)),
+                _ => return Err(format!("unknown argument '{}'\n{}", arg, USAGE)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn parse_size(size: &str) -> Result<(f32, f32), String> {
+    let (width, height) = size
+        .split_once('x')
+        .and_then(|(w, h)| Some((w.parse::<f32>().ok()?, h.parse::<f32>().ok()?)))
+        .filter(|(w, h)| w.is_finite() && h.is_finite())
+        .ok_or_else(|| format!("invalid size '{}'\n{}", size, USAGE))?;
+    if width < MIN_WIDTH || height < MIN_HEIGHT {
+        return Err(format!("size '{}' is smaller than {}x{}\n{}", size, MIN_WIDTH, MIN_HEIGHT, USAGE));
+    }
+    Ok((width, height))
+}
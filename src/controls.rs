@@ -0,0 +1,343 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use bevy::input::InputSystem;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::{FIXED_UPDATE, INPUT};
+
+const BINDINGS_FILE: &str = "config/bindings.ron";
+
+pub struct ControlsPlugin;
+
+// region:    Bindings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Action {
+    MoveLeft,
+    MoveRight,
+    MoveUp,
+    MoveDown,
+    Fire,
+    Charge,
+    Bomb,
+    Focus,
+    Confirm,
+    Pause,
+    Quit,
+}
+
+/// Keyboard and gamepad bindings of every action, stored in `config/bindings.ron`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bindings {
+    pub keys: HashMap<Action, Vec<KeyCode>>,
+    pub buttons: HashMap<Action, Vec<GamepadButtonType>>,
+    /// stick axis used for horizontal movement
+    pub move_axis: GamepadAxisType,
+    /// stick axis used for vertical movement
+    #[serde(default = "default_vertical_axis")]
+    pub vertical_axis: GamepadAxisType,
+    /// stick values below this are ignored
+    pub dead_zone: f32,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        use Action::*;
+        use GamepadButtonType::*;
+        Self {
+            keys: HashMap::from([
+                (MoveLeft, vec![KeyCode::A, KeyCode::Left]),
+                (MoveRight, vec![KeyCode::D, KeyCode::Right]),
+                (MoveUp, vec![KeyCode::W, KeyCode::Up]),
+                (MoveDown, vec![KeyCode::S, KeyCode::Down]),
+                (Fire, vec![KeyCode::Space]),
+                (Charge, vec![KeyCode::X]),
+                (Bomb, vec![KeyCode::B, KeyCode::C]),
+                (Focus, vec![KeyCode::LShift, KeyCode::RShift]),
+                (Confirm, vec![KeyCode::Return]),
+                (Pause, vec![KeyCode::P]),
+                (Quit, vec![KeyCode::Escape]),
+            ]),
+            buttons: HashMap::from([
+                (MoveLeft, vec![DPadLeft]),
+                (MoveRight, vec![DPadRight]),
+                (MoveUp, vec![DPadUp]),
+                (MoveDown, vec![DPadDown]),
+                (Fire, vec![South, RightTrigger]),
+                (Charge, vec![West, LeftTrigger]),
+                (Bomb, vec![North]),
+                (Focus, vec![LeftTrigger2, RightTrigger2]),
+                (Confirm, vec![Start, South]),
+                (Pause, vec![Start]),
+                (Quit, vec![Select]),
+            ]),
+            move_axis: GamepadAxisType::LeftStickX,
+            vertical_axis: default_vertical_axis(),
+            dead_zone: 0.2,
+        }
+    }
+}
+
+// bindings files written before vertical movement have no vertical axis
+fn default_vertical_axis() -> GamepadAxisType {
+    GamepadAxisType::LeftStickY
+}
+
+impl Bindings {
+    /// Read the bindings file, writing the defaults out when there is none yet
+    pub fn load_or_default() -> Self {
+        let path = Path::new(BINDINGS_FILE);
+        match std::fs::read_to_string(path) {
+            Ok(text) => ron::from_str(&text).map(Bindings::with_defaults).unwrap_or_else(|err| {
+                eprintln!("cannot parse {}: {}, using default bindings", path.display(), err);
+                Bindings::default()
+            }),
+            Err(_) => {
+                let bindings = Bindings::default();
+                if let Err(err) = bindings.save(path) {
+                    eprintln!("cannot write {}: {}", path.display(), err);
+                }
+                bindings
+            }
+        }
+    }
+
+    /// Actions added since the file was written keep their default bindings
+    fn with_defaults(self) -> Self {
+        let mut defaults = Bindings::default();
+        defaults.keys.extend(self.keys);
+        defaults.buttons.extend(self.buttons);
+        Self {
+            keys: defaults.keys,
+            buttons: defaults.buttons,
+            ..self
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let pretty = ron::ser::PrettyConfig::default();
+        let text = ron::ser::to_string_pretty(self, pretty).expect("bindings are always serializable");
+        std::fs::write(path, text)
+    }
+}
+// endregion: Bindings
+
+// region:    Actions
+/// State of every action for the current frame, from keyboard and the active gamepad
+#[derive(Default)]
+pub struct Actions {
+    pressed: HashSet<Action>,
+    just_pressed: HashSet<Action>,
+    /// presses kept until a simulation tick takes them, frames can run no tick or several
+    latched: HashSet<Action>,
+    /// analog movement from -1 to 1 on each axis, zero when no stick is used
+    move_axis: Vec2,
+}
+
+impl Actions {
+    pub fn pressed(&self, action: Action) -> bool {
+        self.pressed.contains(&action)
+    }
+
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.just_pressed.contains(&action)
+    }
+
+    /// Swallow a press so a screen entered this frame does not see it too
+    pub fn consume(&mut self, action: Action) {
+        self.just_pressed.remove(&action);
+        self.latched.remove(&action);
+    }
+
+    /// Whether the action was pressed since the last time it was taken
+    pub fn take(&mut self, action: Action) -> bool {
+        self.latched.remove(&action)
+    }
+
+    /// Movement direction, the stick wins over the digital directions
+    pub fn movement(&self) -> Vec2 {
+        if self.move_axis != Vec2::ZERO {
+            return self.move_axis;
+        }
+        let digital = |negative, positive| match (self.pressed(negative), self.pressed(positive)) {
+            (true, false) => -1.,
+            (false, true) => 1.,
+            _ => 0.,
+        };
+        Vec2::new(
+            digital(Action::MoveLeft, Action::MoveRight),
+            digital(Action::MoveDown, Action::MoveUp),
+        )
+    }
+}
+// endregion: Actions
+
+// region:    PlayerInput
+const FIRE: u8 = 1;
+const CONFIRM: u8 = 1 << 1;
+const CHARGE: u8 = 1 << 2;
+const BOMB: u8 = 1 << 3;
+const FOCUS: u8 = 1 << 4;
+
+/// Gameplay input sampled at the start of every tick, read by the player systems instead
+/// of the devices so it can be recorded and played back. Movement is quantized so a
+/// replay reproduces it exactly.
+#[derive(Default, Clone, Copy)]
+pub struct PlayerInput {
+    move_x: i8,
+    move_y: i8,
+    pub fire: bool,
+    pub confirm: bool,
+    pub charge: bool,
+    pub bomb: bool,
+    pub focus: bool,
+    prev_charge: bool,
+    prev_bomb: bool,
+}
+
+impl PlayerInput {
+    /// Movement direction, diagonals are no faster than straight moves
+    pub fn movement(&self) -> Vec2 {
+        let direction = Vec2::new(self.move_x as f32, self.move_y as f32) / i8::MAX as f32;
+        if direction.length() > 1. {
+            direction.normalize()
+        } else {
+            direction
+        }
+    }
+
+    pub fn charge_released(&self) -> bool {
+        self.prev_charge && !self.charge
+    }
+
+    pub fn bomb_pressed(&self) -> bool {
+        self.bomb && !self.prev_bomb
+    }
+
+    pub fn to_frame(self) -> (i8, i8, u8) {
+        let mut bits = 0;
+        for (pressed, bit) in [
+            (self.fire, FIRE),
+            (self.confirm, CONFIRM),
+            (self.charge, CHARGE),
+            (self.bomb, BOMB),
+            (self.focus, FOCUS),
+        ] {
+            if pressed {
+                bits |= bit;
+            }
+        }
+        (self.move_x, self.move_y, bits)
+    }
+
+    /// Keep the buttons of the last tick to find presses and releases, once per tick before
+    /// any frame is applied, a replay applies its frame over the one read from the devices
+    fn next_tick(&mut self) {
+        self.prev_charge = self.charge;
+        self.prev_bomb = self.bomb;
+    }
+
+    pub fn apply_frame(&mut self, (move_x, move_y, bits): (i8, i8, u8)) {
+        self.move_x = move_x;
+        self.move_y = move_y;
+        self.fire = bits & FIRE != 0;
+        self.confirm = bits & CONFIRM != 0;
+        self.charge = bits & CHARGE != 0;
+        self.bomb = bits & BOMB != 0;
+        self.focus = bits & FOCUS != 0;
+    }
+}
+// endregion: PlayerInput
+
+impl Plugin for ControlsPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(Bindings::load_or_default())
+            .insert_resource(Actions::default())
+            .insert_resource(PlayerInput::default())
+            .add_system_to_stage(CoreStage::PreUpdate, actions_update.system().after(InputSystem))
+            .add_system_to_stage(
+                FIXED_UPDATE,
+                controls_from_actions.system().label(INPUT).label("devices"),
+            );
+    }
+}
+
+fn actions_update(
+    bindings: Res<Bindings>,
+    kb: Res<Input<KeyCode>>,
+    buttons: Res<Input<GamepadButton>>,
+    axes: Res<Axis<GamepadAxis>>,
+    mut gamepad_events: EventReader<GamepadEvent>,
+    mut gamepad: Local<Option<Gamepad>>,
+    mut actions: ResMut<Actions>,
+) {
+    // the last connected gamepad is the active one
+    for GamepadEvent(pad, event) in gamepad_events.iter() {
+        match event {
+            GamepadEventType::Connected => *gamepad = Some(*pad),
+            GamepadEventType::Disconnected if *gamepad == Some(*pad) => *gamepad = None,
+            _ => {}
+        }
+    }
+
+    let mut pressed = HashSet::new();
+    let mut just_pressed = HashSet::new();
+    for (action, keys) in bindings.keys.iter() {
+        if keys.iter().any(|&key| kb.pressed(key)) {
+            pressed.insert(*action);
+        }
+        if keys.iter().any(|&key| kb.just_pressed(key)) {
+            just_pressed.insert(*action);
+        }
+    }
+
+    let mut move_axis = Vec2::ZERO;
+    if let Some(pad) = *gamepad {
+        for (action, pad_buttons) in bindings.buttons.iter() {
+            if pad_buttons.iter().any(|&button| buttons.pressed(GamepadButton(pad, button))) {
+                pressed.insert(*action);
+            }
+            if pad_buttons.iter().any(|&button| buttons.just_pressed(GamepadButton(pad, button))) {
+                just_pressed.insert(*action);
+            }
+        }
+        let value = Vec2::new(
+            axes.get(GamepadAxis(pad, bindings.move_axis)).unwrap_or(0.),
+            axes.get(GamepadAxis(pad, bindings.vertical_axis)).unwrap_or(0.),
+        );
+        if value.length() > bindings.dead_zone {
+            move_axis = value.clamp(Vec2::splat(-1.), Vec2::ONE);
+        }
+    }
+
+    actions.latched.extend(just_pressed.iter().copied());
+    actions.pressed = pressed;
+    actions.just_pressed = just_pressed;
+    actions.move_axis = move_axis;
+}
+
+fn controls_from_actions(mut actions: ResMut<Actions>, mut input: ResMut<PlayerInput>) {
+    let movement = (actions.movement() * i8::MAX as f32).round();
+    let (move_x, move_y) = (movement.x as i8, movement.y as i8);
+    let mut bits = 0;
+    if actions.pressed(Action::Fire) {
+        bits |= FIRE;
+    }
+    if actions.pressed(Action::Charge) {
+        bits |= CHARGE;
+    }
+    if actions.pressed(Action::Bomb) {
+        bits |= BOMB;
+    }
+    if actions.pressed(Action::Focus) {
+        bits |= FOCUS;
+    }
+    if actions.take(Action::Confirm) {
+        bits |= CONFIRM;
+    }
+    input.next_tick();
+    input.apply_frame((move_x, move_y, bits));
+}
@@ -0,0 +1,533 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use serde::Deserialize;
+use crate::bulletml::BulletMl;
+use crate::powerup::Powerup;
+use crate::weapon::WeaponKind;
+use crate::{Enemy, GameMode, SPRITE_DIR};
+
+const ENEMIES_FILE: &str = "enemies.ron";
+const WAVES_FILE: &str = "waves.ron";
+const CLASSIC_FILE: &str = "classic.ron";
+const BOSSES_FILE: &str = "bosses.ron";
+const DROPS_FILE: &str = "drops.ron";
+const WEAPONS_FILE: &str = "weapons.ron";
+
+// region:    Definitions
+/// How a bullet moves once fired, angles in degrees
+#[derive(Debug, Clone, Deserialize)]
+pub struct BulletDef {
+    pub speed: f32,
+    #[serde(default)]
+    pub acceleration: f32,
+    #[serde(default)]
+    pub angular_velocity: f32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub enum AimDef {
+    Down,
+    Player,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub enum ShotDef {
+    Spread { count: u32, spread: f32, bullet: BulletDef },
+    Radial { count: u32, bullet: BulletDef },
+    Emitter { bullet: BulletDef, lifetime: f32, pattern: Box<PatternDef> },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PatternDef {
+    pub interval: f32,
+    pub aim: AimDef,
+    #[serde(default)]
+    pub turn: f32,
+    pub shots: Vec<ShotDef>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EnemyDef {
+    pub name: String,
+    pub kind: Enemy,
+    pub sprite: String,
+    pub color: (f32, f32, f32),
+    pub scale: f32,
+    pub speed: f32,
+    pub health: u32,
+    #[serde(default)]
+    pub fire: Vec<PatternDef>,
+    #[serde(default)]
+    pub bulletml: Option<String>,
+    pub points: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FormationDef {
+    pub enemy: String,
+    pub members: u32,
+}
+
+/// Enemies marching as one rectangular grid, a row of `columns` enemies for each archetype in `rows`
+#[derive(Debug, Clone, Deserialize)]
+pub struct GridDef {
+    pub rows: Vec<String>,
+    pub columns: u32,
+    pub spacing: (f32, f32),
+    /// sideways step, and drop when the grid reaches an edge, in pixels
+    pub step: f32,
+    pub drop: f32,
+    /// seconds between steps
+    pub interval: f32,
+    /// seconds between shots fired straight down by the lowest member of a random column
+    pub fire_interval: f32,
+    pub bullet: BulletDef,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct WaveDef {
+    pub max_active: u32,
+    #[serde(default)]
+    pub formations: Vec<FormationDef>,
+    #[serde(default)]
+    pub grid: Option<GridDef>,
+    #[serde(default)]
+    pub boss: Option<String>,
+}
+
+/// Bonus enemy crossing the top of the screen every `interval` seconds while a grid is marching
+#[derive(Debug, Clone, Deserialize)]
+pub struct UfoDef {
+    pub enemy: String,
+    pub interval: f32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub enum BossMovementDef {
+    Hover,
+    Sweep { speed: f32 },
+    Circle { radius: f32, speed: f32 },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PhaseDef {
+    pub until: f32,
+    pub movement: BossMovementDef,
+    pub fire: Vec<PatternDef>,
+    #[serde(default)]
+    pub bulletml: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BossDef {
+    pub name: String,
+    pub sprite: String,
+    pub scale: f32,
+    pub health: u32,
+    pub points: u32,
+    pub phases: Vec<PhaseDef>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WaveScript {
+    pub max_active: u32,
+    pub max_formation_members: u32,
+    pub loop_speed_bonus: f32,
+    pub waves: Vec<WaveDef>,
+    /// destructible bunkers above the player, rebuilt every wave
+    #[serde(default)]
+    pub bunkers: u32,
+    #[serde(default)]
+    pub ufo: Option<UfoDef>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DropDef {
+    pub powerup: Powerup,
+    pub weight: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DropTable {
+    pub chance: f32,
+    pub drops: Vec<DropDef>,
+}
+
+/// One level of a player weapon, spread in degrees
+#[derive(Debug, Clone, Deserialize)]
+pub struct WeaponLevelDef {
+    pub cooldown: f32,
+    pub damage: u32,
+    pub count: u32,
+    #[serde(default)]
+    pub spacing: f32,
+    #[serde(default)]
+    pub spread: f32,
+    pub speed: f32,
+}
+
+/// Piercing shot released when the charge button is let go after `seconds` of holding it
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChargeDef {
+    pub seconds: f32,
+    pub damage: u32,
+    pub speed: f32,
+    pub scale: (f32, f32),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WeaponDef {
+    pub kind: WeaponKind,
+    pub sprite: String,
+    pub color: (f32, f32, f32),
+    pub scale: (f32, f32),
+    #[serde(default)]
+    pub piercing: bool,
+    /// Turn rate of homing shots in degrees per second, 0 flies straight
+    #[serde(default)]
+    pub homing: f32,
+    pub levels: Vec<WeaponLevelDef>,
+    #[serde(default)]
+    pub charge: Option<ChargeDef>,
+}
+
+impl WeaponDef {
+    /// Weapon levels start at 1
+    pub fn level(&self, level: u32) -> &WeaponLevelDef {
+        &self.levels[(level.max(1) as usize - 1).min(self.levels.len() - 1)]
+    }
+}
+// endregion: Definitions
+
+#[derive(Debug)]
+pub enum DefsError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, ron::Error),
+    Invalid(PathBuf, String),
+}
+
+impl fmt::Display for DefsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DefsError::Io(path, err) => write!(f, "cannot read {}: {}", path.display(), err),
+            DefsError::Parse(path, err) => write!(f, "cannot parse {}: {}", path.display(), err),
+            DefsError::Invalid(path, msg) => write!(f, "invalid {}: {}", path.display(), msg),
+        }
+    }
+}
+
+impl std::error::Error for DefsError {}
+
+// Resource
+pub struct GameDefs {
+    pub enemies: Vec<EnemyDef>,
+    pub bosses: Vec<BossDef>,
+    pub waves: WaveScript,
+    pub classic: WaveScript,
+    pub drops: DropTable,
+    pub weapons: Vec<WeaponDef>,
+    pub scripts: HashMap<String, Arc<BulletMl>>,
+}
+
+impl GameDefs {
+    pub fn load() -> Result<Self, DefsError> {
+        let enemies_path = Path::new(SPRITE_DIR).join(ENEMIES_FILE);
+        let waves_path = Path::new(SPRITE_DIR).join(WAVES_FILE);
+        let classic_path = Path::new(SPRITE_DIR).join(CLASSIC_FILE);
+        let bosses_path = Path::new(SPRITE_DIR).join(BOSSES_FILE);
+        let drops_path = Path::new(SPRITE_DIR).join(DROPS_FILE);
+        let weapons_path = Path::new(SPRITE_DIR).join(WEAPONS_FILE);
+        let mut defs = Self {
+            enemies: read_ron(&enemies_path)?,
+            bosses: read_ron(&bosses_path)?,
+            waves: read_ron(&waves_path)?,
+            classic: read_ron(&classic_path)?,
+            drops: read_ron(&drops_path)?,
+            weapons: read_ron(&weapons_path)?,
+            scripts: HashMap::new(),
+        };
+        defs.validate_enemies()
+            .map_err(|msg| DefsError::Invalid(enemies_path, msg))?;
+        defs.validate_bosses()
+            .map_err(|msg| DefsError::Invalid(bosses_path, msg))?;
+        defs.validate_waves(&defs.waves)
+            .map_err(|msg| DefsError::Invalid(waves_path, msg))?;
+        defs.validate_waves(&defs.classic)
+            .map_err(|msg| DefsError::Invalid(classic_path, msg))?;
+        defs.validate_weapons()
+            .map_err(|msg| DefsError::Invalid(weapons_path, msg))?;
+        defs.validate_drops()
+            .map_err(|msg| DefsError::Invalid(drops_path, msg))?;
+
+        // BulletML scripts are parsed once, however many enemies use them
+        let files: HashSet<String> = defs
+            .enemies
+            .iter()
+            .filter_map(|def| def.bulletml.clone())
+            .chain(defs.bosses.iter().flat_map(|def| def.phases.iter().filter_map(|phase| phase.bulletml.clone())))
+            .collect();
+        for file in files {
+            let path = Path::new(SPRITE_DIR).join(&file);
+            let text = std::fs::read_to_string(&path).map_err(|err| DefsError::Io(path.clone(), err))?;
+            let script = BulletMl::parse(&text).map_err(|err| DefsError::Invalid(path, err.to_string()))?;
+            defs.scripts.insert(file, Arc::new(script));
+        }
+        Ok(defs)
+    }
+
+    pub fn bulletml(&self, file: &str) -> Arc<BulletMl> {
+        self.scripts[file].clone()
+    }
+
+    /// Definitions are validated at load time, so a name from the wave script always resolves
+    pub fn enemy(&self, name: &str) -> &EnemyDef {
+        self.enemies
+            .iter()
+            .find(|def| def.name == name)
+            .unwrap_or_else(|| panic!("unknown enemy archetype '{}'", name))
+    }
+
+    pub fn boss(&self, name: &str) -> &BossDef {
+        self.bosses
+            .iter()
+            .find(|def| def.name == name)
+            .unwrap_or_else(|| panic!("unknown boss '{}'", name))
+    }
+
+    /// Wave script of the game mode
+    pub fn script(&self, mode: GameMode) -> &WaveScript {
+        match mode {
+            GameMode::Formations => &self.waves,
+            GameMode::Classic => &self.classic,
+        }
+    }
+
+    pub fn weapon(&self, kind: WeaponKind) -> &WeaponDef {
+        self.weapons
+            .iter()
+            .find(|def| def.kind == kind)
+            .unwrap_or_else(|| panic!("undefined weapon {:?}", kind))
+    }
+
+    fn validate_enemies(&self) -> Result<(), String> {
+        if self.enemies.is_empty() {
+            return Err("no enemy archetypes defined".to_string());
+        }
+        let mut names = HashSet::new();
+        for def in &self.enemies {
+            if !names.insert(def.name.as_str()) {
+                return Err(format!("enemy '{}' is defined twice", def.name));
+            }
+            if !Path::new(SPRITE_DIR).join(&def.sprite).is_file() {
+                return Err(format!("enemy '{}' sprite '{}' not found", def.name, def.sprite));
+            }
+            if def.scale <= 0. || def.speed <= 0. {
+                return Err(format!("enemy '{}' scale and speed must be positive", def.name));
+            }
+            if def.health == 0 {
+                return Err(format!("enemy '{}' health must be at least 1", def.name));
+            }
+            for pattern in &def.fire {
+                validate_pattern(pattern).map_err(|msg| format!("enemy '{}' fire: {}", def.name, msg))?;
+            }
+            if let Enemy::Splitter { child, count } = &def.kind {
+                if *count == 0 {
+                    return Err(format!("enemy '{}' must split into at least 1 child", def.name));
+                }
+                // children cannot split again, so a split always ends
+                match self.enemies.iter().find(|other| &other.name == child) {
+                    None => return Err(format!("enemy '{}' splits into unknown enemy '{}'", def.name, child)),
+                    Some(other) if matches!(other.kind, Enemy::Splitter { .. }) => {
+                        return Err(format!("enemy '{}' cannot split into splitter '{}'", def.name, child))
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_bosses(&self) -> Result<(), String> {
+        let mut names = HashSet::new();
+        for def in &self.bosses {
+            if !names.insert(def.name.as_str()) {
+                return Err(format!("boss '{}' is defined twice", def.name));
+            }
+            if !Path::new(SPRITE_DIR).join(&def.sprite).is_file() {
+                return Err(format!("boss '{}' sprite '{}' not found", def.name, def.sprite));
+            }
+            if def.scale <= 0. || def.health == 0 {
+                return Err(format!("boss '{}' scale and health must be positive", def.name));
+            }
+            if def.phases.is_empty() {
+                return Err(format!("boss '{}' has no phases", def.name));
+            }
+            // each phase lasts until health drops to its `until` fraction, the last one until death
+            let mut previous = 1.;
+            for (i, phase) in def.phases.iter().enumerate() {
+                let last = i + 1 == def.phases.len();
+                if (last && phase.until != 0.) || (!last && (phase.until <= 0. || phase.until >= previous)) {
+                    return Err(format!(
+                        "boss '{}' phase {} until must be below {} and only the last phase ends at 0",
+                        def.name, i + 1, previous
+                    ));
+                }
+                previous = phase.until;
+                for pattern in &phase.fire {
+                    validate_pattern(pattern)
+                        .map_err(|msg| format!("boss '{}' phase {} fire: {}", def.name, i + 1, msg))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_drops(&self) -> Result<(), String> {
+        let table = &self.drops;
+        if !(0. ..=1.).contains(&table.chance) {
+            return Err("drop chance must be between 0 and 1".to_string());
+        }
+        if table.drops.iter().map(|drop| drop.weight).sum::<u32>() == 0 {
+            return Err("drops need a positive total weight".to_string());
+        }
+        for drop in &table.drops {
+            if let Powerup::Weapon(kind) = drop.powerup {
+                if !self.weapons.iter().any(|def| def.kind == kind) {
+                    return Err(format!("drop of undefined weapon {:?}", kind));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_weapons(&self) -> Result<(), String> {
+        let mut kinds = HashSet::new();
+        for def in &self.weapons {
+            if !kinds.insert(def.kind) {
+                return Err(format!("weapon {:?} defined twice", def.kind));
+            }
+            if def.levels.is_empty() {
+                return Err(format!("weapon {:?} has no levels", def.kind));
+            }
+            for (i, level) in def.levels.iter().enumerate() {
+                if level.count == 0 || level.cooldown <= 0. || level.speed <= 0. {
+                    return Err(format!(
+                        "weapon {:?} level {} needs a positive count, cooldown and speed",
+                        def.kind,
+                        i + 1
+                    ));
+                }
+            }
+            if let Some(charge) = &def.charge {
+                if charge.seconds <= 0. || charge.speed <= 0. {
+                    return Err(format!("weapon {:?} charge needs a positive time and speed", def.kind));
+                }
+            }
+        }
+        // the ship always starts with the twin lasers
+        if !kinds.contains(&WeaponKind::Twin) {
+            return Err("the Twin weapon must be defined".to_string());
+        }
+        Ok(())
+    }
+
+    fn validate_waves(&self, script: &WaveScript) -> Result<(), String> {
+        if script.waves.is_empty() {
+            return Err("no waves defined".to_string());
+        }
+        if script.loop_speed_bonus < 0. {
+            return Err("loop_speed_bonus must not be negative".to_string());
+        }
+        for (i, wave) in script.waves.iter().enumerate() {
+            let wave_no = i + 1;
+            if wave.max_active == 0 || wave.max_active > script.max_active {
+                return Err(format!(
+                    "wave {} max_active must be between 1 and {}",
+                    wave_no, script.max_active
+                ));
+            }
+            if wave.formations.is_empty() && wave.grid.is_none() {
+                return Err(format!("wave {} has neither formations nor a grid", wave_no));
+            }
+            if let Some(grid) = &wave.grid {
+                if grid.rows.is_empty() || grid.columns == 0 {
+                    return Err(format!("wave {} grid has no enemies", wave_no));
+                }
+                if grid.interval <= 0. || grid.fire_interval <= 0. {
+                    return Err(format!("wave {} grid intervals must be positive", wave_no));
+                }
+                for row in &grid.rows {
+                    if !self.enemies.iter().any(|def| &def.name == row) {
+                        return Err(format!("wave {} grid references unknown enemy '{}'", wave_no, row));
+                    }
+                }
+            }
+            if let Some(boss) = &wave.boss {
+                if !self.bosses.iter().any(|def| &def.name == boss) {
+                    return Err(format!("wave {} references unknown boss '{}'", wave_no, boss));
+                }
+            }
+            for (j, formation) in wave.formations.iter().enumerate() {
+                if !self.enemies.iter().any(|def| def.name == formation.enemy) {
+                    return Err(format!(
+                        "wave {} formation {} references unknown enemy '{}'",
+                        wave_no, j + 1, formation.enemy
+                    ));
+                }
+                if formation.members == 0 || formation.members > script.max_formation_members {
+                    return Err(format!(
+                        "wave {} formation {} members must be between 1 and {}",
+                        wave_no, j + 1, script.max_formation_members
+                    ));
+                }
+            }
+        }
+        if let Some(ufo) = &script.ufo {
+            if !self.enemies.iter().any(|def| def.name == ufo.enemy) {
+                return Err(format!("ufo references unknown enemy '{}'", ufo.enemy));
+            }
+            if ufo.interval <= 0. {
+                return Err("ufo interval must be positive".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn validate_pattern(pattern: &PatternDef) -> Result<(), String> {
+    if pattern.interval <= 0. {
+        return Err("pattern interval must be positive".to_string());
+    }
+    if pattern.shots.is_empty() {
+        return Err("pattern has no shots".to_string());
+    }
+    for shot in &pattern.shots {
+        let bullet = match shot {
+            ShotDef::Spread { count, bullet, .. } | ShotDef::Radial { count, bullet } => {
+                if *count == 0 {
+                    return Err("shots need at least 1 bullet".to_string());
+                }
+                bullet
+            }
+            ShotDef::Emitter { bullet, lifetime, pattern } => {
+                if *lifetime <= 0. {
+                    return Err("emitter lifetime must be positive".to_string());
+                }
+                validate_pattern(pattern)?;
+                bullet
+            }
+        };
+        // a bullet that never moves would stay on screen forever
+        if bullet.speed < 0. || (bullet.speed == 0. && bullet.acceleration <= 0.) {
+            return Err("bullets must move, speed must be positive or accelerate from 0".to_string());
+        }
+    }
+    Ok(())
+}
+
+fn read_ron<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, DefsError> {
+    let text = std::fs::read_to_string(path).map_err(|err| DefsError::Io(path.to_path_buf(), err))?;
+    ron::from_str(&text).map_err(|err| DefsError::Parse(path.to_path_buf(), err))
+}
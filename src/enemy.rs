@@ -1,10 +1,16 @@
 use crate::{
-    ActiveEnemies, Enemy, FromEnemy, Laser, Speed, SpriteInfos, WinSize, MAX_ENEMIES,
-    MAX_FORMATION_MEMBERS, SCALE, TIME_STEP,
+    simulation, SimulationStep, ActiveEnemies, AppState, Enemy, ExplosionToSpawn, Health, FromEnemy, GameClock, GameMode, GameRng,
+    Laser, PlayField, Player, PlayerState, Points, Speed, SplitToSpawn, SpriteInfos, SpriteSize, Tint, WinSize, DIVE_DELAY,
+    ENEMY_SPAWN_INTERVAL, FIXED_UPDATE, MARCH_MIN_FRACTION, PLAYER_ROW, SCALE, TIME_STEP,
 };
-use bevy::{core::FixedTimestep, prelude::*};
-use rand::{thread_rng, Rng};
+use crate::bulletml::BulletMlRunner;
+use crate::defs::{BulletDef, GameDefs, GridDef};
+use crate::pattern::{spawn_bullet, Bullet, Emitter};
+use crate::wave::WaveDirector;
+use bevy::prelude::*;
+use rand::Rng;
 use std::f32::consts::PI;
+use std::time::Duration;
 
 pub struct EnemyPlugin;
 
@@ -29,106 +35,420 @@ struct FormationMaker {
 }
 
 impl FormationMaker {
-    fn make(&mut self, win_size: &WinSize) -> Formation {
-        match (
-            &self.current_formation,
-            self.current_formation_members >= MAX_FORMATION_MEMBERS,
-        ) {
-            // if first formation or previous formation full
+    fn make(&mut self, rng: &mut impl Rng, win_size: &WinSize, new_formation: bool) -> Formation {
+        match (&self.current_formation, new_formation) {
+            // if first formation or the wave starts a new group
             (None, _) | (_, true) => {
                 // compute the start x/y
-                let mut rng = thread_rng();
                 let h_span = win_size.h / 2. - 100.;
-                let w_span = win_size.w / 4.;
                 let x = if rng.gen::<bool>() {
                     win_size.w
                 } else {
                     -win_size.w
                 };
-                let y = rng.gen_range(-h_span..h_span) as f32;
-                let start = (x, y);
-
-                // compute offset and radius
-                let offset = (rng.gen_range(-w_span..w_span), rng.gen_range(0.0..h_span));
-                let radius = (rng.gen_range(80.0..150.), 100.);
-                let angle: f32 = (y - offset.0).atan2(x - offset.1);
-
-                // create new formation
-                self.group_seq += 1;
-                let group_id = self.group_seq;
-                let formation = Formation {
-                    start,
-                    offset,
-                    radius,
-                    angle,
-                    group_id,
-                };
+                let y = rng.gen_range(-h_span..h_span);
+                let formation = self.new_formation(rng, win_size, (x, y));
 
                 // close, set, and return
                 self.current_formation = Some(formation.clone());
                 self.current_formation_members = 1;
                 formation
             }
-            // if still within the formation count
+            // if still within the current group
             (Some(tmpl), false) => {
                 self.current_formation_members += 1;
                 tmpl.clone()
             }
         }
     }
+
+    /// Formation of its own starting at `start`, without joining the current group
+    fn new_formation(&mut self, rng: &mut impl Rng, win_size: &WinSize, start: (f32, f32)) -> Formation {
+        let (x, y) = start;
+        let h_span = win_size.h / 2. - 100.;
+        let w_span = win_size.w / 4.;
+
+        // compute offset and radius
+        let offset = (rng.gen_range(-w_span..w_span), rng.gen_range(0.0..h_span));
+        let radius = (rng.gen_range(80.0..150.), 100.);
+        let angle: f32 = (y - offset.0).atan2(x - offset.1);
+
+        self.group_seq += 1;
+        Formation {
+            start,
+            offset,
+            radius,
+            angle,
+            group_id: self.group_seq,
+        }
+    }
 }
 // endregion: Formation
 
+// region:    March
+/// Member of the marching grid, the whole grid steps together. An alternative to `Formation`.
+#[derive(Component)]
+struct Marching;
+
+// Resource
+struct MarchGrid {
+    direction: f32,
+    step: f32,
+    drop: f32,
+    // seconds between steps with every member alive, the grid speeds up as they die
+    interval: f32,
+    members: u32,
+    timer: Timer,
+    fire_timer: Timer,
+    bullet: Option<BulletDef>,
+}
+
+impl Default for MarchGrid {
+    fn default() -> Self {
+        Self {
+            direction: 1.,
+            step: 0.,
+            drop: 0.,
+            interval: 1.,
+            members: 0,
+            timer: Timer::from_seconds(1., true),
+            fire_timer: Timer::from_seconds(1., true),
+            bullet: None,
+        }
+    }
+}
+
+impl MarchGrid {
+    fn new(grid: &GridDef, speed_bonus: f32) -> Self {
+        Self {
+            direction: 1.,
+            step: grid.step,
+            drop: grid.drop,
+            interval: grid.interval / speed_bonus,
+            members: grid.rows.len() as u32 * grid.columns,
+            timer: Timer::from_seconds(grid.interval / speed_bonus, true),
+            fire_timer: Timer::from_seconds(grid.fire_interval / speed_bonus, true),
+            bullet: Some(grid.bullet.clone()),
+        }
+    }
+}
+// endregion: March
+
+// Resource
+struct SpawnTimer(Timer);
+
+impl Default for SpawnTimer {
+    fn default() -> Self {
+        Self(Timer::from_seconds(ENEMY_SPAWN_INTERVAL, true))
+    }
+}
+
+// Resource
+struct UfoTimer(Option<Timer>);
+
+// Divers wait in formation until the timer is up
+#[derive(Component)]
+struct DiveTimer(Timer);
+
+// Direction of a diver that left its formation, or of a UFO crossing the screen
+#[derive(Component)]
+struct Diving(Vec2);
+
 impl Plugin for EnemyPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         app
             .insert_resource(FormationMaker::default())
-            .add_system(enemy_laser_movement.system())
-            .add_system(enemy_movement.system())
+            .insert_resource(SpawnTimer::default())
+            .insert_resource(MarchGrid::default())
+            .insert_resource(UfoTimer(None))
             .add_system_set(
-                SystemSet::new()
-                    .with_run_criteria(FixedTimestep::step(1.0))
-                    .with_system(enemy_spawn.system()),
+                SystemSet::on_enter(AppState::Playing).with_system(enemy_reset.system()),
             )
-            .add_system_set(
-                SystemSet::new()
-                    .with_run_criteria(FixedTimestep::step(0.9))
-                    .with_system(enemy_fire.system()),
+            .add_system_set_to_stage(
+                FIXED_UPDATE,
+                simulation(SimulationStep::Enemy)
+                    .with_system(enemy_laser_movement.system().label("enemy_laser_movement"))
+                    .with_system(enemy_movement.system().label("enemy_movement").after("enemy_laser_movement"))
+                    .with_system(enemy_dive.system().label("enemy_dive").after("enemy_movement"))
+                    .with_system(enemy_diving.system().label("enemy_diving").after("enemy_dive"))
+                    .with_system(enemy_spawn.system().label("enemy_spawn").after("enemy_diving"))
+                    .with_system(enemy_grid_spawn.system().label("enemy_grid_spawn").after("enemy_spawn"))
+                    .with_system(enemy_march.system().label("enemy_march").after("enemy_grid_spawn"))
+                    .with_system(enemy_march_fire.system().label("enemy_march_fire").after("enemy_march"))
+                    .with_system(enemy_invasion.system().label("enemy_invasion").after("enemy_march_fire"))
+                    .with_system(enemy_ufo.system().label("enemy_ufo").after("enemy_invasion"))
+                    .with_system(enemy_split.system().after("enemy_ufo")),
             );
     }
 }
 
+fn enemy_reset(
+    mut formation_maker: ResMut<FormationMaker>,
+    mut spawn_timer: ResMut<SpawnTimer>,
+    mut march_grid: ResMut<MarchGrid>,
+    mut ufo_timer: ResMut<UfoTimer>,
+    mode: Res<GameMode>,
+    defs: Res<GameDefs>,
+) {
+    *formation_maker = FormationMaker::default();
+    *spawn_timer = SpawnTimer::default();
+    *march_grid = MarchGrid::default();
+    let ufo = &defs.script(*mode).ufo;
+    ufo_timer.0 = ufo.as_ref().map(|ufo| Timer::from_seconds(ufo.interval, true));
+}
+
+#[allow(clippy::too_many_arguments)]
 fn enemy_spawn(
     mut commands: Commands,
     mut active_enemies: ResMut<ActiveEnemies>,
     mut formation_maker: ResMut<FormationMaker>,
+    mut wave_director: ResMut<WaveDirector>,
+    mut spawn_timer: ResMut<SpawnTimer>,
+    mut game_rng: ResMut<GameRng>,
+    clock: Res<GameClock>,
     win_size: Res<WinSize>,
     materials: Res<SpriteInfos>,
+    defs: Res<GameDefs>,
 ) {
-    if active_enemies.0 < MAX_ENEMIES {
-        // get the formation and start x/y
-        let formation = formation_maker.make(&win_size);
-        let (x, y) = formation.start;
+    if !spawn_timer.0.tick(clock.delta()).just_finished() {
+        return;
+    }
+    if active_enemies.0 >= wave_director.max_active() {
+        return;
+    }
+    if let Some((new_formation, archetype)) = wave_director.next_member() {
+        let formation = formation_maker.make(&mut game_rng.rng, &win_size, new_formation);
+        let position = Vec3::new(formation.start.0, formation.start.1, 10.);
+        let speed_bonus = wave_director.speed_bonus();
+        let enemy = spawn_enemy(&mut commands, &defs, &archetype, &materials, position, speed_bonus);
+        commands.entity(enemy).insert(formation);
+        active_enemies.0 += 1;
+    }
+}
 
-        // spawn enemy
-        commands
-            .spawn_bundle(SpriteBundle {
-                texture: materials.enemy.0.clone(),
-                transform: Transform {
-                    translation: Vec3::new(x, y, 10.0),
-                    scale: Vec3::new(SCALE, SCALE, 1.),
-                    ..Default::default()
-                },
-                ..Default::default()
-            })
-            .insert(Enemy)
-            .insert(Speed::default())
-            .insert(formation);
+/// The whole grid of a wave enters at once, centered at the top of the play field
+fn enemy_grid_spawn(
+    mut commands: Commands,
+    mut active_enemies: ResMut<ActiveEnemies>,
+    mut wave_director: ResMut<WaveDirector>,
+    mut march_grid: ResMut<MarchGrid>,
+    field: Res<PlayField>,
+    materials: Res<SpriteInfos>,
+    defs: Res<GameDefs>,
+) {
+    let grid = match wave_director.take_grid() {
+        Some(grid) => grid,
+        None => return,
+    };
+    let speed_bonus = wave_director.speed_bonus();
+    let (dx, dy) = grid.spacing;
+    let left = -dx * (grid.columns - 1) as f32 / 2.;
+    let top = field.h / 2. - 100.;
+    for (row, archetype) in grid.rows.iter().enumerate() {
+        for column in 0..grid.columns {
+            let position = Vec3::new(left + dx * column as f32, top - dy * row as f32, 10.);
+            let enemy = spawn_enemy(&mut commands, &defs, archetype, &materials, position, speed_bonus);
+            commands.entity(enemy).insert(Marching);
+            active_enemies.0 += 1;
+        }
+    }
+    *march_grid = MarchGrid::new(&grid, speed_bonus);
+}
 
-        active_enemies.0 += 1;
+/// Step the grid sideways, or down and back once its outer members reach the edge of the play field
+fn enemy_march(
+    clock: Res<GameClock>,
+    field: Res<PlayField>,
+    mut march_grid: ResMut<MarchGrid>,
+    mut query: Query<(&mut Transform, &SpriteSize), With<Marching>>,
+) {
+    // the fewer members are left, the faster the grid marches
+    let alive = query.iter().count();
+    if alive == 0 {
+        return;
+    }
+    let fraction = (alive as f32 / march_grid.members as f32).max(MARCH_MIN_FRACTION);
+    let interval = Duration::from_secs_f32(march_grid.interval * fraction);
+    if march_grid.timer.duration() != interval {
+        march_grid.timer.set_duration(interval);
+    }
+    if !march_grid.timer.tick(clock.delta()).just_finished() {
+        return;
+    }
+    let edge = field.w / 2.;
+    let step = march_grid.step * march_grid.direction;
+    let at_edge = query.iter().any(|(tf, size)| {
+        let half_width = size.0.x * tf.scale.x / 2.;
+        (tf.translation.x + step).abs() + half_width > edge
+    });
+    let offset = if at_edge {
+        march_grid.direction = -march_grid.direction;
+        Vec3::new(0., -march_grid.drop, 0.)
+    } else {
+        Vec3::new(step, 0., 0.)
+    };
+    for (mut tf, _) in query.iter_mut() {
+        tf.translation += offset;
     }
 }
 
+/// A grid marching down to the row of the ship ends the run
+fn enemy_invasion(
+    mut commands: Commands,
+    mut player_state: ResMut<PlayerState>,
+    clock: Res<GameClock>,
+    field: Res<PlayField>,
+    sprite_infos: Res<SpriteInfos>,
+    player_query: Query<(Entity, &Transform), With<Player>>,
+    query: Query<(&Transform, &SpriteSize), With<Marching>>,
+) {
+    if player_state.is_game_over() {
+        return;
+    }
+    let player_row = -field.h / 2. + PLAYER_ROW + sprite_infos.player.1.y * SCALE / 2.;
+    let invaded = query
+        .iter()
+        .any(|(tf, size)| tf.translation.y - size.0.y * tf.scale.y / 2. <= player_row);
+    if !invaded {
+        return;
+    }
+    for (player_entity, player_tf) in player_query.iter() {
+        commands.entity(player_entity).despawn_recursive();
+        commands.spawn().insert(ExplosionToSpawn(player_tf.translation));
+    }
+    player_state.invaded(clock.seconds_since_startup());
+}
+
+/// Only the lowest member of a column has a clear shot
+fn enemy_march_fire(
+    mut commands: Commands,
+    mut march_grid: ResMut<MarchGrid>,
+    mut game_rng: ResMut<GameRng>,
+    clock: Res<GameClock>,
+    materials: Res<SpriteInfos>,
+    query: Query<&Transform, With<Marching>>,
+) {
+    if !march_grid.fire_timer.tick(clock.delta()).just_finished() {
+        return;
+    }
+    let bullet = match &march_grid.bullet {
+        Some(bullet) => bullet,
+        None => return,
+    };
+    let members: Vec<Vec3> = query.iter().map(|tf| tf.translation).collect();
+    if members.is_empty() {
+        return;
+    }
+    let picked = members[game_rng.rng.gen_range(0..members.len())];
+    let shooter = members
+        .iter()
+        .filter(|member| (member.x - picked.x).abs() < 1.)
+        .min_by(|a, b| a.y.partial_cmp(&b.y).unwrap())
+        .unwrap_or(&picked);
+    spawn_bullet(&mut commands, &materials, *shooter, 0., bullet);
+}
+
+/// Bonus enemy crossing the top of the screen while a grid is marching
+#[allow(clippy::too_many_arguments)]
+fn enemy_ufo(
+    mut commands: Commands,
+    mut active_enemies: ResMut<ActiveEnemies>,
+    mut ufo_timer: ResMut<UfoTimer>,
+    mut game_rng: ResMut<GameRng>,
+    clock: Res<GameClock>,
+    win_size: Res<WinSize>,
+    mode: Res<GameMode>,
+    wave_director: Res<WaveDirector>,
+    materials: Res<SpriteInfos>,
+    defs: Res<GameDefs>,
+    marching_query: Query<Entity, With<Marching>>,
+) {
+    let (timer, ufo) = match (ufo_timer.0.as_mut(), &defs.script(*mode).ufo) {
+        (Some(timer), Some(ufo)) => (timer, ufo),
+        _ => return,
+    };
+    if marching_query.iter().next().is_none() || !timer.tick(clock.delta()).just_finished() {
+        return;
+    }
+    let direction = if game_rng.rng.gen::<bool>() { 1. } else { -1. };
+    let position = Vec3::new(-direction * (win_size.w / 2. + 40.), win_size.h / 2. - 60., 10.);
+    let speed_bonus = wave_director.speed_bonus();
+    let enemy = spawn_enemy(&mut commands, &defs, &ufo.enemy, &materials, position, speed_bonus);
+    commands.entity(enemy).insert(Diving(Vec2::new(direction, 0.)));
+    active_enemies.0 += 1;
+}
+
+/// Children of destroyed splitters, each in a formation of its own starting where the splitter died,
+/// they were counted as active enemies when the splitter was destroyed
+#[allow(clippy::too_many_arguments)]
+fn enemy_split(
+    mut commands: Commands,
+    mut formation_maker: ResMut<FormationMaker>,
+    mut game_rng: ResMut<GameRng>,
+    wave_director: Res<WaveDirector>,
+    win_size: Res<WinSize>,
+    materials: Res<SpriteInfos>,
+    defs: Res<GameDefs>,
+    query: Query<(Entity, &SplitToSpawn)>,
+) {
+    for (split_entity, split) in query.iter() {
+        for _ in 0..split.count {
+            let start = (split.position.x, split.position.y);
+            let formation = formation_maker.new_formation(&mut game_rng.rng, &win_size, start);
+            let speed_bonus = wave_director.speed_bonus();
+            let enemy = spawn_enemy(&mut commands, &defs, &split.archetype, &materials, split.position, speed_bonus);
+            commands.entity(enemy).insert(formation);
+        }
+        commands.entity(split_entity).despawn();
+    }
+}
+
+/// Spawn an enemy of the archetype without any movement, the caller adds a formation, grid or dive
+fn spawn_enemy(
+    commands: &mut Commands,
+    defs: &GameDefs,
+    archetype: &str,
+    materials: &SpriteInfos,
+    position: Vec3,
+    speed_bonus: f32,
+) -> Entity {
+    let def = defs.enemy(archetype);
+    let (texture, size) = materials.enemies[&def.name].clone();
+    let (r, g, b) = def.color;
+
+    // spawn enemy
+    let mut enemy = commands.spawn_bundle(SpriteBundle {
+        texture,
+        sprite: Sprite {
+            color: Color::rgb(r, g, b),
+            ..Default::default()
+        },
+        transform: Transform {
+            translation: Vec3::new(position.x, position.y, 10.0),
+            scale: Vec3::new(def.scale, def.scale, 1.),
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    enemy
+        .insert(def.kind.clone())
+        .insert(Speed(def.speed * speed_bonus))
+        .insert(Points(def.points))
+        .insert(Health(def.health))
+        .insert(Tint(Color::rgb(r, g, b)))
+        .insert(SpriteSize(size));
+    if !def.fire.is_empty() {
+        enemy.insert(Emitter::new(&def.fire));
+    }
+    if let Some(file) = &def.bulletml {
+        enemy.insert(BulletMlRunner::new(defs.bulletml(file)));
+    }
+    if def.kind == Enemy::Diver {
+        enemy.insert(DiveTimer(Timer::from_seconds(DIVE_DELAY, false)));
+    }
+    enemy.id()
+}
+
 fn enemy_movement(mut query: Query<(&mut Transform, &Speed, &mut Formation), (With<Enemy>,)>) {
     // for each enemy
     for (mut tf, speed, mut formation) in query.iter_mut() {
@@ -174,41 +494,64 @@ fn enemy_movement(mut query: Query<(&mut Transform, &Speed, &mut Formation), (Wi
     }
 }
 
-fn enemy_fire(
+/// Divers whose timer is up break formation and head for where the player is now
+fn enemy_dive(
     mut commands: Commands,
-    textures: Res<SpriteInfos>,
-    enemy_query: Query<&Transform, With<Enemy>>,
+    clock: Res<GameClock>,
+    player_query: Query<&Transform, With<Player>>,
+    mut query: Query<(Entity, &Transform, &mut DiveTimer)>,
 ) {
-    // for each enemy shoot laser
-    for &tf in enemy_query.iter() {
-        let x = tf.translation.x;
-        let y = tf.translation.y;
-        // spawn enemy laser sprite
+    for (entity, tf, mut timer) in query.iter_mut() {
+        if !timer.0.tick(clock.delta()).just_finished() {
+            continue;
+        }
+        let target = match player_query.iter().next() {
+            Some(player_tf) => (player_tf.translation - tf.translation).truncate(),
+            None => -Vec2::Y,
+        };
         commands
-            .spawn_bundle(SpriteBundle {
-                texture: textures.enemy_laser.0.clone(),
-                transform: Transform {
-                    translation: Vec3::new(x, y - 15., 0.),
-                    scale: Vec3::new(SCALE, -SCALE, 1.),
-                    ..Default::default()
-                },
-                ..Default::default()
-            })
-            .insert(Laser)
-            .insert(FromEnemy)
-            .insert(Speed::default());
+            .entity(entity)
+            .remove::<Formation>()
+            .remove::<Marching>()
+            .remove::<DiveTimer>()
+            .insert(Diving(target.normalize_or_zero()));
+    }
+}
+
+fn enemy_diving(
+    mut commands: Commands,
+    win_size: Res<WinSize>,
+    mut active_enemies: ResMut<ActiveEnemies>,
+    mut query: Query<(Entity, &Speed, &Diving, &Health, &mut Transform)>,
+) {
+    for (entity, speed, diving, health, mut tf) in query.iter_mut() {
+        tf.translation += diving.0.extend(0.) * speed.0 * TIME_STEP;
+
+        // divers that fly off screen are gone for good, without scoring, unless they were
+        // destroyed this tick and are already counted out
+        let half = Vec2::new(win_size.w, win_size.h) / 2. + 50.;
+        let off_screen = tf.translation.x.abs() > half.x || tf.translation.y.abs() > half.y;
+        if off_screen && health.0 > 0 {
+            commands.entity(entity).despawn();
+            active_enemies.0 = active_enemies.0.saturating_sub(1);
+        }
     }
 }
 
+#[allow(clippy::type_complexity)]
 fn enemy_laser_movement(
     mut commands: Commands,
     win_size: Res<WinSize>,
-    mut laser_query: Query<(Entity, &Speed, &mut Transform), (With<Laser>, With<FromEnemy>)>,
+    mut laser_query: Query<(Entity, &mut Bullet, &mut Transform), (With<Laser>, With<FromEnemy>)>,
 ) {
     // for each laser from enemy
-    for (entity, speed, mut tf) in laser_query.iter_mut() {
-        tf.translation.y -= speed.0 * TIME_STEP;
-        if tf.translation.y < -win_size.h / 2. - 50. {
+    for (entity, mut bullet, mut tf) in laser_query.iter_mut() {
+        tf.translation += bullet.step(TIME_STEP).extend(0.);
+        tf.rotation = bullet.rotation();
+        if tf.translation.y < -win_size.h / 2. - 50.
+            || tf.translation.y > win_size.h / 2. + 50.
+            || tf.translation.x.abs() > win_size.w / 2. + 50.
+        {
             commands.entity(entity).despawn();
         }
     }
@@ -0,0 +1,122 @@
+use bevy::prelude::*;
+use crate::controls::{Action, Actions, PlayerInput};
+use crate::hud::screen_text;
+use crate::{despawn_with, simulation, SimulationStep, AppState, GameClock, FIXED_UPDATE, PlayerState, Score, CONTINUE_SECONDS, HUD_FONT};
+
+pub struct GameOverPlugin;
+
+#[derive(Component)]
+struct ContinueText;
+#[derive(Component)]
+struct GameOverScreen;
+
+// Resource
+struct ContinueCountdown(Timer);
+
+impl Default for ContinueCountdown {
+    fn default() -> Self {
+        Self(Timer::from_seconds(CONTINUE_SECONDS, false))
+    }
+}
+
+impl Plugin for GameOverPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(ContinueCountdown::default())
+            .add_system_set_to_stage(FIXED_UPDATE, simulation(SimulationStep::GameOver).with_system(game_over_continue.system()))
+            .add_system_set(
+                SystemSet::on_exit(AppState::Playing).with_system(despawn_with::<ContinueText>.system()),
+            )
+            .add_system_set(
+                SystemSet::on_enter(AppState::GameOver).with_system(game_over_setup.system()),
+            )
+            .add_system_set(
+                SystemSet::on_update(AppState::GameOver).with_system(game_over_leave.system()),
+            )
+            .add_system_set(
+                SystemSet::on_exit(AppState::GameOver)
+                    .with_system(despawn_with::<GameOverScreen>.system()),
+            );
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn game_over_continue(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    clock: Res<GameClock>,
+    input: Res<PlayerInput>,
+    mut state: ResMut<State<AppState>>,
+    mut player_state: ResMut<PlayerState>,
+    mut score: ResMut<Score>,
+    mut countdown: ResMut<ContinueCountdown>,
+    mut text_query: Query<(Entity, &mut Text), With<ContinueText>>,
+) {
+    // an extra life earned by a laser still in flight can revive the player
+    if !player_state.is_game_over() {
+        for (entity, _) in text_query.iter_mut() {
+            commands.entity(entity).despawn();
+        }
+        return;
+    }
+
+    // several ticks can run before the transition happens, so overwrite instead of queueing
+    if player_state.continues == 0 {
+        state.overwrite_set(AppState::GameOver).unwrap();
+        return;
+    }
+
+    let (entity, mut text) = match text_query.get_single_mut() {
+        Ok(found) => found,
+        Err(_) => {
+            countdown.0.reset();
+            commands
+                .spawn_bundle(screen_text(asset_server.load(HUD_FONT), "", 28., 45., 12.))
+                .insert(ContinueText);
+            return;
+        }
+    };
+
+    // the countdown ran out, the run is over
+    if countdown.0.tick(clock.delta()).finished() {
+        state.overwrite_set(AppState::GameOver).unwrap();
+    } else if input.confirm {
+        player_state.continued();
+        score.reset();
+        commands.entity(entity).despawn();
+    } else {
+        let remaining = CONTINUE_SECONDS - countdown.0.elapsed_secs();
+        text.sections[0].value = format!(
+            "CONTINUE? {}  (ENTER, {} left)",
+            remaining.ceil(),
+            player_state.continues
+        );
+    }
+}
+
+fn game_over_setup(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    score: Res<Score>,
+) {
+    let font = asset_server.load(HUD_FONT);
+    commands
+        .spawn_bundle(screen_text(font.clone(), "GAME OVER", 48., 35., 20.))
+        .insert(GameOverScreen);
+    commands
+        .spawn_bundle(screen_text(
+            font,
+            &format!("final score {}  -  press ENTER", score.value),
+            24.,
+            50.,
+            16.,
+        ))
+        .insert(GameOverScreen);
+}
+
+fn game_over_leave(mut actions: ResMut<Actions>, mut state: ResMut<State<AppState>>) {
+    if actions.just_pressed(Action::Confirm) {
+        actions.consume(Action::Confirm);
+        state.set(AppState::Menu).unwrap();
+    }
+}
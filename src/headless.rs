@@ -0,0 +1,84 @@
+use bevy::app::AppExit;
+use bevy::ecs::event::{Events, ManualEventReader};
+use bevy::asset::AssetPlugin;
+use bevy::input::InputPlugin;
+use bevy::prelude::*;
+use crate::defs::GameDefs;
+use crate::replay::{save_recording, ReplayMode};
+use crate::wave::WaveDirector;
+use crate::{add_gameplay, AppState, GameClock, GameMode, PlayField, PlayerState, Score, WinSize, TIME_STEP};
+
+/// Run the gameplay without a window or GPU, one `TIME_STEP` tick per update
+/// until `ticks` ran out or the game is over
+pub fn run(
+    defs: GameDefs,
+    seed: u64,
+    mode: GameMode,
+    replay_mode: ReplayMode,
+    win_size: WinSize,
+    field: PlayField,
+    ticks: u32,
+) {
+    let playback = matches!(replay_mode, ReplayMode::Playback(_));
+
+    let mut app = App::new();
+    app
+        .add_plugins(MinimalPlugins)
+        .add_plugin(AssetPlugin)
+        .add_plugin(InputPlugin)
+        .add_asset::<Image>()
+        .add_asset::<TextureAtlas>()
+        .add_asset::<Font>()
+        .insert_resource(win_size)
+        .insert_resource(field)
+        .insert_resource(mode)
+        .add_state(AppState::Menu)
+        .add_system_to_stage(CoreStage::PreUpdate, clock_fixed_step.system());
+    // leave the menu right away, a replay does that itself
+    if !playback {
+        app.add_system_set(SystemSet::on_update(AppState::Menu).with_system(headless_start.system()));
+    }
+    add_gameplay(&mut app, defs, seed, replay_mode);
+
+    let mut exit_reader = ManualEventReader::<AppExit>::default();
+
+    loop {
+        app.update();
+        if app.world.get_resource::<GameClock>().unwrap().ticks >= ticks as u64 {
+            save_recording(&app.world);
+            break;
+        }
+        let state = app.world.get_resource::<State<AppState>>().unwrap();
+        if *state.current() == AppState::GameOver {
+            break;
+        }
+        let exit_events = app.world.get_resource::<Events<AppExit>>().unwrap();
+        if exit_reader.iter(exit_events).next().is_some() {
+            break;
+        }
+    }
+
+    let clock = app.world.get_resource::<GameClock>().unwrap();
+    let score = app.world.get_resource::<Score>().unwrap();
+    let player_state = app.world.get_resource::<PlayerState>().unwrap();
+    let wave_director = app.world.get_resource::<WaveDirector>().unwrap();
+    println!(
+        "ticks {}  wave {}  score {}  lives {}  continues {}",
+        clock.ticks,
+        wave_director.wave(),
+        score.value,
+        player_state.lives,
+        player_state.continues
+    );
+}
+
+fn headless_start(mut state: ResMut<State<AppState>>) {
+    state.set(AppState::Playing).unwrap();
+}
+
+/// Exactly one simulation tick per update
+fn clock_fixed_step(state: Res<State<AppState>>, mut clock: ResMut<GameClock>) {
+    if *state.current() == AppState::Playing {
+        clock.accumulate(TIME_STEP as f64);
+    }
+}
@@ -0,0 +1,154 @@
+use bevy::prelude::*;
+use crate::boss::Boss;
+use crate::{Health, PlayerState, Score, HUD_FONT};
+
+pub struct HudPlugin;
+
+#[derive(Component)]
+struct ScoreText;
+
+// Boss name and health bar, only visible while a boss is on screen
+#[derive(Component)]
+struct BossBar;
+#[derive(Component)]
+struct BossBarFill;
+
+impl Plugin for HudPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_startup_system(hud_setup.system())
+            .add_system(hud_score.system())
+            .add_system(hud_boss.system());
+    }
+}
+
+fn hud_setup(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+) {
+    commands.spawn_bundle(UiCameraBundle::default());
+
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(5.),
+                    left: Val::Px(10.),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font: asset_server.load(HUD_FONT),
+                    font_size: 20.,
+                    color: Color::WHITE,
+                },
+                Default::default(),
+            ),
+            ..Default::default()
+        })
+        .insert(ScoreText);
+
+    let hidden = Visibility { is_visible: false };
+    commands
+        .spawn_bundle(TextBundle {
+            visibility: hidden.clone(),
+            ..screen_text(asset_server.load(HUD_FONT), "", 16., 9., 20.)
+        })
+        .insert(BossBar);
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Percent(12.5),
+                    left: Val::Percent(20.),
+                    ..Default::default()
+                },
+                size: Size::new(Val::Percent(60.), Val::Px(10.)),
+                ..Default::default()
+            },
+            color: UiColor(Color::rgb(0.25, 0.05, 0.05)),
+            visibility: hidden.clone(),
+            ..Default::default()
+        })
+        .insert(BossBar)
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(NodeBundle {
+                    style: Style {
+                        size: Size::new(Val::Percent(100.), Val::Percent(100.)),
+                        ..Default::default()
+                    },
+                    color: UiColor(Color::rgb(0.9, 0.1, 0.1)),
+                    visibility: hidden,
+                    ..Default::default()
+                })
+                .insert(BossBar)
+                .insert(BossBarFill);
+        });
+}
+
+fn hud_score(
+    score: Res<Score>,
+    player_state: Res<PlayerState>,
+    mut query: Query<&mut Text, With<ScoreText>>,
+) {
+    if !score.is_changed() && !player_state.is_changed() {
+        return;
+    }
+    for mut text in query.iter_mut() {
+        text.sections[0].value = format!(
+            "SCORE {:>7}  x{}   BEST {:>7}\nLIVES {}   BOMBS {}   GRAZE {}",
+            score.value, score.multiplier, score.best, player_state.lives, player_state.bombs, score.graze
+        );
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn hud_boss(
+    boss_query: Query<(&Boss, &Health)>,
+    mut query: Query<(&mut Visibility, &mut Style, Option<&mut Text>, Option<&BossBarFill>), With<BossBar>>,
+) {
+    let boss = boss_query.iter().next();
+    for (mut visibility, mut style, text, fill) in query.iter_mut() {
+        visibility.is_visible = boss.is_some();
+        if let Some((boss, health)) = boss {
+            if let Some(mut text) = text {
+                text.sections[0].value = boss.name().to_uppercase();
+            }
+            if fill.is_some() {
+                let percent = 100. * health.0 as f32 / boss.max_health() as f32;
+                style.size.width = Val::Percent(percent);
+            }
+        }
+    }
+}
+
+/// Absolutely positioned text used by the menu, pause and game over screens
+pub fn screen_text(font: Handle<Font>, value: &str, font_size: f32, top: f32, left: f32) -> TextBundle {
+    TextBundle {
+        style: Style {
+            position_type: PositionType::Absolute,
+            position: Rect {
+                top: Val::Percent(top),
+                left: Val::Percent(left),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        text: Text::with_section(
+            value,
+            TextStyle {
+                font,
+                font_size,
+                color: Color::WHITE,
+            },
+            Default::default(),
+        ),
+        ..Default::default()
+    }
+}
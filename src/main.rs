@@ -3,61 +3,256 @@
 
 mod player;
 mod enemy;
+mod score;
+mod hud;
+mod game_over;
+mod menu;
+mod wave;
+mod defs;
+mod cli;
+mod headless;
+mod controls;
+mod replay;
+mod timestep;
+mod boss;
+mod pattern;
+mod bulletml;
+mod powerup;
+mod weapon;
+mod bomb;
+mod bunker;
 
 use bevy::math::Vec3Swizzles;
 use std::borrow::BorrowMut;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::process::exit;
+use std::time::Duration;
 use std::ptr::addr_of;
 use bevy::app::AppExit;
 use bevy::diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin};
+use bevy::ecs::schedule::ShouldRun;
 use bevy::prelude::*;
 use bevy::render::texture::ImageType;
 use bevy::sprite::collide_aabb::collide;
 use bevy::window::CloseWindow;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use crate::bomb::{BombPlugin, Shockwave};
+use crate::boss::BossPlugin;
+use crate::bunker::{Bunker, BunkerPlugin};
+use crate::bulletml::BulletMlPlugin;
+use crate::cli::Options;
+use crate::controls::{Action, Actions, ControlsPlugin};
+use crate::defs::GameDefs;
 use crate::enemy::EnemyPlugin;
+use crate::game_over::GameOverPlugin;
+use crate::hud::HudPlugin;
+use crate::menu::MenuPlugin;
+use crate::pattern::PatternPlugin;
 use crate::player::PlayerPlugin;
+use crate::powerup::{Pickup, PowerupPlugin};
+use crate::replay::{add_replay, Replay, ReplayMode};
+use crate::timestep::TimestepPlugin;
+use crate::score::ScorePlugin;
+use crate::wave::WavePlugin;
+use crate::weapon::{Piercing, Weapon, WeaponKind, WeaponPlugin};
 use crate::StartupStage::Startup;
 
 const SPRITE_DIR: &str = "assets";
 
 const PLAYER_SPRITE: &str = "player_a_01.png";
-const ENEMY_SPRITE: &str = "enemy_a_01.png";
-const PLAYER_LASER_SPRITE: &str = "laser_a_01.png";
 const ENEMY_LASER_SPRITE: &str = "laser_b_01.png";
 const EXPLOSION_SHEET: &str = "explo_a_sheet.png";
-const MAX_ENEMIES: u32 = 2;
-const MAX_FORMATION_MEMBERS: u32 = 2;
+const HUD_FONT: &str = "fonts/DejaVuSans-Bold.ttf";
+const WAVE_BREAK: f32 = 3.;
+const WAVE_BANNER: f32 = 2.;
 const SCALE: f32 = 0.5;
 const PLAYER_RESPAWN_DELAY: f64 = 2.;
+// height of the ship above the bottom of the play field
+const PLAYER_ROW: f32 = 25.;
+const COMBO_STEP: u32 = 5;
+const MAX_MULTIPLIER: u32 = 8;
+const PLAYER_LIVES: u32 = 3;
+const EXTRA_LIFE_EVERY: u32 = 5_000;
+const MAX_CONTINUES: u32 = 3;
+const CONTINUE_SECONDS: f32 = 9.;
+
+const HIT_FLASH_SECONDS: f32 = 0.08;
+// sprite colors multiply the texture, overdriving them washes the sprite out to white
+const HIT_FLASH_COLOR: Color = Color::rgb(4., 4., 4.);
 
 const TIME_STEP: f32 = 1. / 60.;
+const MAX_CATCH_UP: f64 = 0.25;
+const FIXED_UPDATE: &str = "fixed_update";
+const INPUT: &str = "input";
+const SIMULATE: &str = "simulate";
+const WINDOW_WIDTH: f32 = 598.;
+const WINDOW_HEIGHT: f32 = 676.;
+const ENEMY_SPAWN_INTERVAL: f32 = 1.;
+const DIVE_DELAY: f32 = 4.;
+// a marching grid never steps faster than this fraction of its starting interval
+const MARCH_MIN_FRACTION: f32 = 0.05;
+const BOSS_TRANSITION: f32 = 2.;
+const BOSS_EXPLOSIONS: u32 = 12;
+const BOSS_EXPLOSION_INTERVAL: f32 = 0.12;
+// waves it takes the BulletML $rank difficulty to go from 0 to 1
+const RANK_WAVES: f32 = 10.;
+const PICKUP_SPEED: f32 = 120.;
+const POWERUP_SECONDS: f32 = 10.;
+const SHIELD_SECONDS: f32 = 6.;
+// rapid fire scales the weapon cooldown
+const RAPID_FIRE_FACTOR: f32 = 0.5;
+const SPREAD_SHOT_ANGLE: f32 = 12.;
+const SCORE_GEM_POINTS: u32 = 250;
+const MAX_BOMBS: u32 = 5;
+// focus mode slows the ship down, tightens its shots and shows the hitbox
+const FOCUS_SPEED_FACTOR: f32 = 0.4;
+const FOCUS_SPREAD_FACTOR: f32 = 0.4;
+// side of the square enemy lasers have to hit, much smaller than the ship sprite
+const PLAYER_HITBOX: f32 = 6.;
+// enemy lasers passing this close to the ship without hitting it score a graze
+const GRAZE_RADIUS: f32 = 28.;
+const GRAZE_POINTS: u32 = 10;
+const GRAZES_PER_COMBO: u32 = 4;
+const SPARK_SECONDS: f32 = 0.15;
+const BOMBS_PER_LIFE: u32 = 2;
+const BOMB_DAMAGE: u32 = 10;
+const BOMB_ITEM_POINTS: u32 = 20;
+const BOMB_INVULNERABLE_SECONDS: f32 = 2.;
+const RESPAWN_INVULNERABLE_SECONDS: f32 = 3.;
+// enemy lasers this close to a respawning ship are removed
+const RESPAWN_CLEAR_RADIUS: f32 = 150.;
+const BLINK_INTERVAL: f32 = 0.1;
+const SHOCKWAVE_SECONDS: f32 = 0.6;
 
 pub struct SpriteInfos {
     player: (Handle<Image>, Vec2),
-    player_laser: (Handle<Image>, Vec2),
     enemy_laser: (Handle<Image>, Vec2),
-    enemy: (Handle<Image>, Vec2),
+    enemies: HashMap<String, (Handle<Image>, Vec2)>,
+    bosses: HashMap<String, (Handle<Image>, Vec2)>,
+    weapons: HashMap<WeaponKind, (Handle<Image>, Vec2)>,
     explosion: Handle<TextureAtlas>
 
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum AppState {
+    Menu,
+    Playing,
+    Paused,
+    GameOver,
+}
+
+/// Gameplay clock, frame time is accumulated and consumed in whole `TIME_STEP` ticks
+/// by the fixed update stage, so the simulation does not depend on the frame rate
+#[derive(Default)]
+pub struct GameClock {
+    accumulator: f64,
+    ticks: u64,
+}
+
+impl GameClock {
+    fn accumulate(&mut self, seconds: f64) {
+        // do not try to catch up on long stalls, slow the game down instead
+        self.accumulator = (self.accumulator + seconds).min(MAX_CATCH_UP);
+    }
+    fn consume_tick(&mut self) -> bool {
+        let step = TIME_STEP as f64;
+        if self.accumulator >= step {
+            self.accumulator -= step;
+            self.ticks += 1;
+            true
+        } else {
+            false
+        }
+    }
+    fn delta(&self) -> Duration {
+        Duration::from_secs_f32(TIME_STEP)
+    }
+    fn seconds_since_startup(&self) -> f64 {
+        self.ticks as f64 * TIME_STEP as f64
+    }
+    /// How far into the next tick the clock is, from 0 to 1
+    fn overstep(&self) -> f32 {
+        (self.accumulator / TIME_STEP as f64) as f32
+    }
+}
+
+/// Source of all gameplay randomness, reseeded at the start of every run
+pub struct GameRng {
+    seed: u64,
+    rng: ChaCha8Rng,
+}
+
+impl GameRng {
+    fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+        }
+    }
+    fn reset(&mut self) {
+        self.rng = ChaCha8Rng::seed_from_u64(self.seed);
+    }
+}
+
 struct WinSize {
     w: f32,
     h: f32,
 }
 
+/// Formation waves from waves.ron, or the classic rules of classic.ron with a marching grid, bunkers and a UFO
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GameMode {
+    #[default]
+    Formations,
+    Classic,
+}
+
+/// Rectangle the player moves in, centered in the window and possibly smaller than it
+struct PlayField {
+    w: f32,
+    h: f32,
+}
+
+impl PlayField {
+    /// Clamp a position so a sprite of `size` stays inside the field
+    fn clamp(&self, position: Vec2, size: Vec2) -> Vec2 {
+        let max = ((Vec2::new(self.w, self.h) - size) / 2.).max(Vec2::ZERO);
+        position.clamp(-max, max)
+    }
+}
+
 struct PlayerState {
     is_alive: bool,
-    last_shot: f64
+    last_shot: f64,
+    lives: u32,
+    continues: u32,
+    next_extra_life: u32,
+    bombs: u32,
+    // seconds left on the timed power-ups
+    spread_shot: f32,
+    rapid_fire: f32,
+    shield: f32,
+    // weapon the ship respawns with
+    weapon: Weapon,
 }
 
 impl Default for PlayerState {
     fn default() -> Self {
         Self {
             is_alive: false,
-            last_shot: 0.
+            last_shot: 0.,
+            lives: PLAYER_LIVES,
+            continues: MAX_CONTINUES,
+            next_extra_life: EXTRA_LIFE_EVERY,
+            bombs: BOMBS_PER_LIFE,
+            spread_shot: 0.,
+            rapid_fire: 0.,
+            shield: 0.,
+            weapon: Weapon::default(),
         }
     }
 }
@@ -66,32 +261,174 @@ impl PlayerState {
     fn shot(&mut self, time: f64) {
         self.is_alive = false;
         self.last_shot = time;
+        self.lives = self.lives.saturating_sub(1);
+        // the next ship comes with a fresh bomb stock
+        self.bombs = self.bombs.max(BOMBS_PER_LIFE);
+        // timed power-ups are lost with the ship
+        self.spread_shot = 0.;
+        self.rapid_fire = 0.;
+        self.shield = 0.;
     }
     fn spawned(&mut self) {
         self.is_alive = true;
         self.last_shot = 0.;
     }
+    /// The marching grid reached the ship, the run is over without a chance to continue
+    fn invaded(&mut self, time: f64) {
+        if self.is_alive {
+            self.shot(time);
+        }
+        self.lives = 0;
+        self.continues = 0;
+    }
+    fn is_game_over(&self) -> bool {
+        !self.is_alive && self.lives == 0
+    }
+    fn continued(&mut self) {
+        self.lives = PLAYER_LIVES;
+        self.continues -= 1;
+        self.next_extra_life = EXTRA_LIFE_EVERY;
+        self.last_shot = 0.;
+    }
+}
+
+pub struct Score {
+    value: u32,
+    best: u32,
+    combo: u32,
+    multiplier: u32,
+    graze: u32,
+    // part of `value` scored by grazes, which do not earn extra lives
+    graze_points: u32,
+}
+
+impl Default for Score {
+    fn default() -> Self {
+        Self {
+            value: 0,
+            best: 0,
+            combo: 0,
+            multiplier: 1,
+            graze: 0,
+            graze_points: 0,
+        }
+    }
+}
+
+impl Score {
+    fn add_kill(&mut self, points: u32) {
+        self.value += points * self.multiplier;
+        self.best = self.best.max(self.value);
+        self.add_combo();
+    }
+    /// Every GRAZES_PER_COMBO grazes count as a kill towards the multiplier
+    fn add_graze(&mut self) {
+        self.graze += 1;
+        self.graze_points += GRAZE_POINTS * self.multiplier;
+        self.add_bonus(GRAZE_POINTS);
+        if self.graze.is_multiple_of(GRAZES_PER_COMBO) {
+            self.add_combo();
+        }
+    }
+    fn add_combo(&mut self) {
+        self.combo += 1;
+        self.multiplier = (1 + self.combo / COMBO_STEP).min(MAX_MULTIPLIER);
+    }
+    /// Points that do not count towards the combo
+    fn add_bonus(&mut self, points: u32) {
+        self.value += points * self.multiplier;
+        self.best = self.best.max(self.value);
+    }
+    fn reset_combo(&mut self) {
+        self.combo = 0;
+        self.multiplier = 1;
+    }
+    fn reset(&mut self) {
+        self.value = 0;
+        self.graze = 0;
+        self.graze_points = 0;
+        self.reset_combo();
+    }
+}
+
+// Event
+pub struct EnemyKilled {
+    points: u32,
+    position: Vec3,
 }
 
 #[derive(Component)]
 struct ActiveEnemies(u32);
-#[derive(Component)]
-struct Enemy;
+/// Enemy archetype, decides how an enemy moves, fires and dies
+#[derive(Component, Debug, Clone, PartialEq, Deserialize)]
+pub enum Enemy {
+    /// orbits its formation
+    Grunt,
+    /// leaves its formation after `DIVE_DELAY` and dives at the player
+    Diver,
+    /// slow and heavily armored
+    Tank,
+    /// aims its shots at the player
+    Sniper,
+    /// breaks into `count` enemies of the `child` archetype when destroyed
+    Splitter { child: String, count: u32 },
+    /// end of wave boss, only spawned from bosses.ron
+    #[serde(skip_deserializing)]
+    Boss,
+}
 #[derive(Component)]
 struct Player;
 #[derive(Component)]
 struct Laser;
 #[derive(Component)]
-struct PlayerReadyFire(bool);
-#[derive(Component)]
 struct Explosion;
+/// Enemy laser inside GRAZE_RADIUS of the ship, it scores a graze once it leaves without a hit
+#[derive(Component)]
+struct Grazing;
+/// Enemy lasers only score one graze each
+#[derive(Component)]
+struct Grazed;
+#[derive(Component)]
+struct Spark(Timer);
 #[derive(Component)]
 struct ExplosionToSpawn(Vec3);
 #[derive(Component)]
+struct ExplosionSequence {
+    position: Vec3,
+    remaining: u32,
+    timer: Timer,
+}
+#[derive(Component)]
+struct SplitToSpawn {
+    archetype: String,
+    count: u32,
+    position: Vec3,
+}
+#[derive(Component)]
 struct FromPlayer;
 #[derive(Component)]
 struct FromEnemy;
 
+#[derive(Component)]
+struct Points(u32);
+#[derive(Component)]
+struct SpriteSize(Vec2);
+#[derive(Component)]
+struct Health(u32);
+#[derive(Component)]
+struct Damage(u32);
+#[derive(Component)]
+struct HitFlash(Timer);
+/// Color of an enemy archetype, the sprite goes back to it after a hit flash
+#[derive(Component)]
+struct Tint(Color);
+/// Enemy lasers fly through the player while it lasts, the sprite blinks meanwhile
+#[derive(Component)]
+struct Invulnerable(Timer);
+/// Enemies with a shield absorb lasers without taking damage
+#[derive(Component)]
+struct Shielded;
+
 #[derive(Component)]
 struct Speed(f32);
 
@@ -102,35 +439,132 @@ impl Default for Speed {
 }
 
 fn main() {
-    App::new()
-        .add_plugin(FrameTimeDiagnosticsPlugin::default())
+    let options = Options::from_args().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        exit(2);
+    });
+    let defs = GameDefs::load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        exit(1);
+    });
+
+    let replay_mode = match &options.replay {
+        Some(path) => ReplayMode::Playback(Replay::load(path).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            exit(1);
+        })),
+        None => ReplayMode::Record(options.record.clone()),
+    };
+
+    let seed = match &replay_mode {
+        ReplayMode::Playback(replay) => replay.seed,
+        ReplayMode::Record(_) => options.seed.unwrap_or_else(rand::random),
+    };
+    println!("seed {}", seed);
+    let mode = match &replay_mode {
+        ReplayMode::Playback(replay) => replay.mode,
+        ReplayMode::Record(_) => options.mode,
+    };
+
+    // a replay plays back in the window and field it was recorded in
+    let ((width, height), (field_w, field_h)) = match &replay_mode {
+        ReplayMode::Playback(replay) => (replay.size, replay.field),
+        ReplayMode::Record(_) => (
+            (options.width, options.height),
+            options.field.unwrap_or((options.width, options.height)),
+        ),
+    };
+    let field = PlayField { w: field_w, h: field_h };
+
+    if let Some(ticks) = options.headless {
+        let win_size = WinSize { w: width, h: height };
+        headless::run(defs, seed, mode, replay_mode, win_size, field, ticks);
+        return;
+    }
+
+    let mut app = App::new();
+    app
+        .add_plugin(FrameTimeDiagnosticsPlugin)
         .insert_resource(WindowDescriptor {
             title: "Rust Invaders!".to_string(),
-            width: 598.0,
-            height: 676.0,
+            width,
+            height,
             ..Default::default()
         })
-        .insert_resource(ActiveEnemies(0))
+        .insert_resource(field)
+        .insert_resource(mode)
         .add_plugins(DefaultPlugins)
-        .add_plugin(PlayerPlugin)
-        .add_plugin(EnemyPlugin)
+        .add_state(AppState::Menu)
         .add_startup_system(setup.label("start"))
         .add_startup_system(close_game.after("start"))
         .add_system(close_game)
-        .add_system(player_laser_hit_enemy.system())
-        .add_system(enemy_laser_hit_player.system())
-        .add_system(explosion_to_spawn.system())
-        .add_system(animate_explosion.system())
-        .run();
+        .add_system_to_stage(CoreStage::PreUpdate, clock_from_time.system());
+    add_gameplay(&mut app, defs, seed, replay_mode);
+    app.run();
+}
+
+/// Everything shared by the windowed game and the headless simulation
+fn add_gameplay(app: &mut App, defs: GameDefs, seed: u64, replay_mode: ReplayMode) {
+    app
+        .insert_resource(ActiveEnemies(0))
+        .insert_resource(GameRng::new(seed))
+        .insert_resource(GameClock::default())
+        .insert_resource(defs)
+        .add_plugin(TimestepPlugin)
+        .add_plugin(ControlsPlugin)
+        // collisions run first, before the systems reading the kills they send
+        .add_system_set_to_stage(
+            FIXED_UPDATE,
+            simulation(SimulationStep::Collisions)
+                .with_system(player_laser_hit_enemy.system().label("player_laser_hit_enemy").label("collisions"))
+                .with_system(enemy_laser_hit_player.system().label("enemy_laser_hit_player").label("collisions").after("player_laser_hit_enemy"))
+                .with_system(hit_flash.system().label("hit_flash").after("enemy_laser_hit_player"))
+                .with_system(invulnerable_timer.system().label("invulnerable_timer").after("hit_flash"))
+                .with_system(spark_fade.system().label("spark_fade").after("invulnerable_timer"))
+                .with_system(explosion_sequence.system().label("explosion_sequence").after("spark_fade"))
+                .with_system(explosion_to_spawn.system().label("explosion_to_spawn").after("explosion_sequence"))
+                .with_system(animate_explosion.system().after("explosion_to_spawn"))
+        )
+        .add_plugin(BombPlugin)
+        .add_plugin(PlayerPlugin)
+        .add_plugin(EnemyPlugin)
+        .add_plugin(WavePlugin)
+        .add_plugin(ScorePlugin)
+        .add_plugin(HudPlugin)
+        .add_plugin(MenuPlugin)
+        .add_plugin(GameOverPlugin)
+        .add_plugin(BossPlugin)
+        .add_plugin(PatternPlugin)
+        .add_plugin(BulletMlPlugin)
+        .add_plugin(PowerupPlugin)
+        .add_plugin(WeaponPlugin)
+        .add_plugin(BunkerPlugin)
+        .add_startup_system(load_sprites.system())
+        .add_system_set(
+            SystemSet::on_enter(AppState::Playing)
+                .with_system(game_reset.system())
+        )
+        .add_system_set(
+            SystemSet::on_exit(AppState::Playing)
+                .with_system(despawn_with::<Player>.system())
+                .with_system(despawn_with::<Enemy>.system())
+                .with_system(despawn_with::<Laser>.system())
+                .with_system(despawn_with::<Explosion>.system())
+                .with_system(despawn_with::<ExplosionToSpawn>.system())
+                .with_system(despawn_with::<ExplosionSequence>.system())
+                .with_system(despawn_with::<SplitToSpawn>.system())
+                .with_system(despawn_with::<Pickup>.system())
+                .with_system(despawn_with::<Shockwave>.system())
+                .with_system(despawn_with::<Spark>.system())
+                .with_system(despawn_with::<Bunker>.system())
+        );
+    add_replay(app, replay_mode, seed);
 }
 
 
 fn setup(
     mut commands: Commands,
-    asset_server: Res<AssetServer>,
-    mut images: ResMut<Assets<Image>>,
-    mut texture_atlases: ResMut<Assets<TextureAtlas>>,
-    mut windows: ResMut<Windows>
+    mut windows: ResMut<Windows>,
 ) {
     commands.spawn_bundle(OrthographicCameraBundle::new_2d());
     let mut window = windows.get_primary_mut().unwrap();
@@ -141,28 +575,134 @@ fn setup(
     });
 
     window.set_position(IVec2::new(1300, 80));
+}
 
+fn load_sprites(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut images: ResMut<Assets<Image>>,
+    mut texture_atlases: ResMut<Assets<TextureAtlas>>,
+    defs: Res<GameDefs>,
+) {
     // create the main resources
     let texture_handle = asset_server.load(EXPLOSION_SHEET);
     let texture_atlas = TextureAtlas::from_grid(texture_handle, Vec2::new(64.0, 64.0), 4, 4);
 
     commands.insert_resource(SpriteInfos {
         player: load_image(&mut images, PLAYER_SPRITE),
-        player_laser: load_image(&mut images, PLAYER_LASER_SPRITE),
         enemy_laser: load_image(&mut images, ENEMY_LASER_SPRITE),
-        enemy: load_image(&mut images, ENEMY_SPRITE),
+        enemies: defs
+            .enemies
+            .iter()
+            .map(|def| (def.name.clone(), load_image(&mut images, &def.sprite)))
+            .collect(),
+        bosses: defs
+            .bosses
+            .iter()
+            .map(|def| (def.name.clone(), load_image(&mut images, &def.sprite)))
+            .collect(),
+        weapons: defs
+            .weapons
+            .iter()
+            .map(|def| (def.kind, load_image(&mut images, &def.sprite)))
+            .collect(),
         explosion: texture_atlases.add(texture_atlas),
     });
+}
 
+fn game_reset(
+    mut player_state: ResMut<PlayerState>,
+    mut score: ResMut<Score>,
+    mut active_enemies: ResMut<ActiveEnemies>,
+    mut game_rng: ResMut<GameRng>,
+) {
+    game_rng.reset();
+    *player_state = PlayerState::default();
+    score.reset();
+    active_enemies.0 = 0;
 }
 
-fn render_background()
+/// The game clock only runs while playing, so menus and pauses do not count towards gameplay timers
+fn clock_from_time(time: Res<Time>, state: Res<State<AppState>>, mut clock: ResMut<GameClock>) {
+    if *state.current() == AppState::Playing {
+        clock.accumulate(time.delta_seconds_f64());
+    }
+}
+
+fn run_if_playing(state: Res<State<AppState>>) -> ShouldRun {
+    if *state.current() == AppState::Playing {
+        ShouldRun::Yes
+    } else {
+        ShouldRun::No
+    }
+}
+
+/// Steps of a simulation tick, every step runs after the one declared before it. Bevy orders
+/// systems without a dependency between them differently in every process, so the same seed
+/// and input would not play the same run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, SystemLabel)]
+enum SimulationStep {
+    Collisions,
+    Bomb,
+    Player,
+    Enemy,
+    Wave,
+    Score,
+    GameOver,
+    Boss,
+    Pattern,
+    BulletMl,
+    Powerup,
+    Weapon,
+    Bunker,
+}
+
+impl SimulationStep {
+    fn previous(self) -> Option<Self> {
+        use SimulationStep::*;
+        match self {
+            Collisions => None,
+            Bomb => Some(Collisions),
+            Player => Some(Bomb),
+            Enemy => Some(Player),
+            Wave => Some(Enemy),
+            Score => Some(Wave),
+            GameOver => Some(Score),
+            Boss => Some(GameOver),
+            Pattern => Some(Boss),
+            BulletMl => Some(Pattern),
+            Powerup => Some(BulletMl),
+            Weapon => Some(Powerup),
+            Bunker => Some(Weapon),
+        }
+    }
+}
+
+/// Gameplay systems of one step of the fixed update stage, run every tick while playing,
+/// the systems within a step chain with `.after()` themselves
+fn simulation(step: SimulationStep) -> SystemSet {
+    let set = SystemSet::new()
+        .with_run_criteria(run_if_playing.system())
+        .label(SIMULATE)
+        .label(step)
+        .after(INPUT);
+    match step.previous() {
+        Some(previous) => set.after(previous),
+        None => set,
+    }
+}
+
+fn despawn_with<T: Component>(mut commands: Commands, query: Query<Entity, With<T>>) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
 
 fn close_game(
-    keyboard_input: Res<Input<KeyCode>>,
+    actions: Res<Actions>,
     mut exit: EventWriter<AppExit>
 ) {
-    if keyboard_input.pressed(KeyCode::Escape) {
+    if actions.pressed(Action::Quit) {
         exit.send(AppExit);
     }
 }
@@ -170,7 +710,7 @@ fn close_game(
 
 fn load_image(images: &mut ResMut<Assets<Image>>, path: &str) -> (Handle<Image>, Vec2) {
     let path = Path::new(SPRITE_DIR).join(path);
-    let bytes = std::fs::read(&path).expect(&format!("Cannot find {}", path.display()));
+    let bytes = std::fs::read(&path).unwrap_or_else(|_| panic!("Cannot find {}", path.display()));
     let image = Image::from_buffer(&bytes, ImageType::MimeType("image/png")).unwrap();
     let size = image.texture_descriptor.size;
     let size = Vec2::new(size.width as f32, size.height as f32);
@@ -179,23 +719,36 @@ fn load_image(images: &mut ResMut<Assets<Image>>, path: &str) -> (Handle<Image>,
 }
 
 
+#[allow(clippy::type_complexity)]
 fn player_laser_hit_enemy(
     mut commands: Commands,
-    sprite_infos: Res<SpriteInfos>,
-    mut laser_query: Query<(Entity, &Transform), (With<Laser>, With<FromPlayer>)>,
-    mut enemy_query: Query<(Entity, &Transform), With<Enemy>>,
+    mut laser_query: Query<
+        (Entity, &Transform, &Damage, &SpriteSize, Option<&mut Piercing>),
+        (With<Laser>, With<FromPlayer>),
+    >,
+    mut enemy_query: Query<(
+        Entity,
+        &Enemy,
+        &Transform,
+        &SpriteSize,
+        &Points,
+        &mut Health,
+        &mut Sprite,
+        Option<&Shielded>,
+    )>,
     mut active_enemies: ResMut<ActiveEnemies>,
+    mut enemy_killed: EventWriter<EnemyKilled>,
 ) {
 
-    let mut enemies_blasted: HashSet<Entity> = HashSet::new();
+    for (player_laser_entity, player_laser_tf, damage, player_laser_size, mut piercing) in laser_query.iter_mut() {
+        let player_laser_size = player_laser_size.0;
+        let player_laser_scale = player_laser_tf.scale.abs().xy();
+        for (enemy_entity, enemy, enemy_tf, enemy_size, points, mut health, mut sprite, shielded) in
+            enemy_query.iter_mut()
+        {
 
-    for (player_laser_entity, player_laser_tf) in laser_query.iter_mut() {
-        let player_laser_size = sprite_infos.player_laser.1;
-        let player_laser_scale = Vec2::from(player_laser_tf.scale.abs().xy());
-        for (enemy_entity, enemy_tf) in enemy_query.iter_mut() {
-
-            let enemy_size = sprite_infos.enemy.1;
-            let enemy_scale = Vec2::from(enemy_tf.scale.xy());
+            let enemy_size = enemy_size.0;
+            let enemy_scale = enemy_tf.scale.xy();
 
             let collision = collide(
                 player_laser_tf.translation,
@@ -204,65 +757,233 @@ fn player_laser_hit_enemy(
                 enemy_size * enemy_scale,
             );
 
-            if let Some(_) = collision {
-                if enemies_blasted.get(&enemy_entity).is_none() {
-                    // remove the enemy
-                    commands.entity(enemy_entity).despawn();
-                    active_enemies.0 -= 1;
+            // lasers fly through enemies already destroyed this tick, by a laser or a bomb
+            if collision.is_some() && health.0 > 0 {
+                // piercing lasers carry on, damaging each enemy once
+                if let Some(piercing) = piercing.as_mut() {
+                    if piercing.0.contains(&enemy_entity) {
+                        continue;
+                    }
+                    piercing.0.push(enemy_entity);
+                }
+                // remove the laser, it only damages one enemy, shields stop piercing lasers too
+                if piercing.is_none() || shielded.is_some() {
+                    commands.entity(player_laser_entity).despawn();
+                }
+                if shielded.is_some() {
+                    break;
+                }
 
+                health.0 = health.0.saturating_sub(damage.0);
+                if health.0 > 0 {
+                    sprite.color = HIT_FLASH_COLOR;
                     commands
-                        .spawn()
-                        .insert(ExplosionToSpawn(enemy_tf.translation.clone()));
-
-                    enemies_blasted.insert(enemy_entity);
+                        .entity(enemy_entity)
+                        .insert(HitFlash(Timer::from_seconds(HIT_FLASH_SECONDS, false)));
+                } else {
+                    destroy_enemy(
+                        &mut commands,
+                        enemy_entity,
+                        enemy,
+                        enemy_tf.translation,
+                        points.0,
+                        &mut active_enemies,
+                        &mut enemy_killed,
+                    );
+                }
+                if piercing.is_none() {
+                    break;
                 }
-                // remove the laser
-                commands.entity(player_laser_entity).despawn();
             }
         }
     }
 }
 
+/// Remove a destroyed enemy, scoring it and leaving the explosions or children it breaks into
+fn destroy_enemy(
+    commands: &mut Commands,
+    entity: Entity,
+    enemy: &Enemy,
+    position: Vec3,
+    points: u32,
+    active_enemies: &mut ActiveEnemies,
+    enemy_killed: &mut EventWriter<EnemyKilled>,
+) {
+    commands.entity(entity).despawn();
+    active_enemies.0 = active_enemies.0.saturating_sub(1);
+
+    enemy_killed.send(EnemyKilled { points, position });
+
+    match enemy {
+        Enemy::Boss => {
+            commands.spawn().insert(ExplosionSequence {
+                position,
+                remaining: BOSS_EXPLOSIONS,
+                timer: Timer::from_seconds(BOSS_EXPLOSION_INTERVAL, true),
+            });
+        }
+        Enemy::Splitter { child, count } => {
+            // the children count as active right away, the wave must not end before they spawn
+            active_enemies.0 += count;
+            commands.spawn().insert(ExplosionToSpawn(position));
+            commands.spawn().insert(SplitToSpawn {
+                archetype: child.clone(),
+                count: *count,
+                position,
+            });
+        }
+        _ => {
+            commands.spawn().insert(ExplosionToSpawn(position));
+        }
+    }
+}
+
+fn hit_flash(
+    mut commands: Commands,
+    clock: Res<GameClock>,
+    mut query: Query<(Entity, &mut HitFlash, &mut Sprite, Option<&Tint>)>,
+) {
+    for (entity, mut flash, mut sprite, tint) in query.iter_mut() {
+        if flash.0.tick(clock.delta()).finished() {
+            sprite.color = tint.map_or(Color::WHITE, |tint| tint.0);
+            commands.entity(entity).remove::<HitFlash>();
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
 fn enemy_laser_hit_player(
     mut commands: Commands,
     sprite_infos: Res<SpriteInfos>,
     mut player_state: ResMut<PlayerState>,
-    time: Res<Time>,
-    laser_query: Query<(Entity, &Transform), (With<Laser>, With<FromEnemy>)>,
-    player_query: Query<(Entity, &Transform), With<Player>>,
+    mut score: ResMut<Score>,
+    clock: Res<GameClock>,
+    laser_query: Query<(Entity, &Transform, Option<&Grazing>, Option<&Grazed>), (With<Laser>, With<FromEnemy>)>,
+    player_query: Query<(Entity, &Transform, &Weapon, Option<&Invulnerable>), With<Player>>,
 ) {
-    if let Ok((player_entity, player_tf)) = player_query.get_single() {
-        let player_size = sprite_infos.player.1;
-        let player_scale = Vec2::from(player_tf.scale.xy());
-
+    if let Ok((player_entity, player_tf, weapon, invulnerable)) = player_query.get_single() {
+        if invulnerable.is_some() {
+            return;
+        }
         // for each enemy laser
-        for (enemy_laser_entity, enemy_laser_tf) in laser_query.iter() {
-            let enemy_laser_scale = Vec2::from(enemy_laser_tf.scale.abs().xy());
+        for (enemy_laser_entity, enemy_laser_tf, grazing, grazed) in laser_query.iter() {
+            let enemy_laser_scale = enemy_laser_tf.scale.abs().xy();
             let enemy_laser_size = sprite_infos.enemy_laser.1;
 
             let collision = collide(
                 enemy_laser_tf.translation,
                 enemy_laser_size * enemy_laser_scale,
                 player_tf.translation,
-                player_size * player_scale,
+                Vec2::splat(PLAYER_HITBOX),
             );
 
             // process collision
-            if let Some(_) = collision {
+            if collision.is_some() {
+                // the shield soaks up lasers while it lasts
+                if player_state.shield > 0. {
+                    commands.entity(enemy_laser_entity).despawn();
+                    continue;
+                }
                 // remove the player
-                commands.entity(player_entity).despawn();
-                player_state.shot(time.seconds_since_startup());
+                commands.entity(player_entity).despawn_recursive();
+                player_state.shot(clock.seconds_since_startup());
+                player_state.weapon = weapon.downgraded();
+                score.reset_combo();
                 // remove the laser
                 commands.entity(enemy_laser_entity).despawn();
                 // spawn the ExplosionToSpawn entity
                 commands
                     .spawn()
-                    .insert(ExplosionToSpawn(player_tf.translation.clone()));
+                    .insert(ExplosionToSpawn(player_tf.translation));
+                // a ship that got hit scores none of the near misses it was still dodging
+                for (entity, _, grazing, _) in laser_query.iter() {
+                    if grazing.is_some() && entity != enemy_laser_entity {
+                        commands.entity(entity).remove::<Grazing>().insert(Grazed);
+                    }
+                }
+                break;
+            }
+
+            // near misses score once per laser, when the laser got away without hitting
+            let offset = enemy_laser_tf.translation.truncate() - player_tf.translation.truncate();
+            let close = offset.length() < GRAZE_RADIUS;
+            if grazed.is_none() && grazing.is_none() && close {
+                commands.entity(enemy_laser_entity).insert(Grazing);
+            } else if grazing.is_some() && !close {
+                commands.entity(enemy_laser_entity).remove::<Grazing>().insert(Grazed);
+                score.add_graze();
+                commands
+                    .spawn_bundle(SpriteBundle {
+                        sprite: Sprite {
+                            color: Color::rgb(1., 1., 0.6),
+                            custom_size: Some(Vec2::splat(4.)),
+                            ..Default::default()
+                        },
+                        transform: Transform::from_translation(player_tf.translation + (offset / 2.).extend(1.)),
+                        ..Default::default()
+                    })
+                    .insert(Spark(Timer::from_seconds(SPARK_SECONDS, false)));
             }
         }
     }
 }
 
+fn spark_fade(
+    mut commands: Commands,
+    clock: Res<GameClock>,
+    mut query: Query<(Entity, &mut Spark, &mut Sprite)>,
+) {
+    for (entity, mut spark, mut sprite) in query.iter_mut() {
+        if spark.0.tick(clock.delta()).finished() {
+            commands.entity(entity).despawn();
+        } else {
+            sprite.color.set_a(1. - spark.0.percent());
+        }
+    }
+}
+
+fn invulnerable_timer(
+    mut commands: Commands,
+    clock: Res<GameClock>,
+    mut query: Query<(Entity, &mut Invulnerable, &mut Visibility)>,
+) {
+    for (entity, mut invulnerable, mut visibility) in query.iter_mut() {
+        if invulnerable.0.tick(clock.delta()).finished() {
+            visibility.is_visible = true;
+            commands.entity(entity).remove::<Invulnerable>();
+        } else {
+            let blinks = (invulnerable.0.elapsed_secs() / BLINK_INTERVAL) as u32;
+            visibility.is_visible = blinks.is_multiple_of(2);
+        }
+    }
+}
+
+/// Chain of explosions scattered around a destroyed boss
+fn explosion_sequence(
+    mut commands: Commands,
+    clock: Res<GameClock>,
+    mut game_rng: ResMut<GameRng>,
+    mut query: Query<(Entity, &mut ExplosionSequence)>,
+) {
+    for (entity, mut sequence) in query.iter_mut() {
+        if !sequence.timer.tick(clock.delta()).just_finished() {
+            continue;
+        }
+        let offset = Vec3::new(
+            game_rng.rng.gen_range(-60.0..60.),
+            game_rng.rng.gen_range(-40.0..40.),
+            0.,
+        );
+        commands
+            .spawn()
+            .insert(ExplosionToSpawn(sequence.position + offset));
+        sequence.remaining -= 1;
+        if sequence.remaining == 0 {
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
 fn explosion_to_spawn(
     mut commands: Commands,
     query: Query<(Entity, &ExplosionToSpawn)>,
@@ -287,7 +1008,7 @@ fn explosion_to_spawn(
 
 fn animate_explosion(
     mut commands: Commands,
-    time: Res<Time>,
+    clock: Res<GameClock>,
     texture_atlases: Res<Assets<TextureAtlas>>,
     mut query: Query<
         (
@@ -300,7 +1021,7 @@ fn animate_explosion(
     >,
 ) {
     for (entity, mut timer, mut sprite, texture_atlas_handle) in query.iter_mut() {
-        timer.tick(time.delta());
+        timer.tick(clock.delta());
         if timer.finished() {
             let texture_atlas = texture_atlases.get(texture_atlas_handle).unwrap();
             sprite.index += 1;
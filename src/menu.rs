@@ -0,0 +1,66 @@
+use bevy::prelude::*;
+use crate::controls::{Action, Actions};
+use crate::hud::screen_text;
+use crate::{despawn_with, AppState, HUD_FONT};
+
+pub struct MenuPlugin;
+
+#[derive(Component)]
+struct MenuScreen;
+#[derive(Component)]
+struct PauseScreen;
+
+impl Plugin for MenuPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_system_set(SystemSet::on_enter(AppState::Menu).with_system(menu_setup.system()))
+            .add_system_set(SystemSet::on_update(AppState::Menu).with_system(menu_start.system()))
+            .add_system_set(
+                SystemSet::on_exit(AppState::Menu).with_system(despawn_with::<MenuScreen>.system()),
+            )
+            .add_system_set(SystemSet::on_update(AppState::Playing).with_system(pause_toggle.system()))
+            .add_system_set(SystemSet::on_enter(AppState::Paused).with_system(pause_setup.system()))
+            .add_system_set(SystemSet::on_update(AppState::Paused).with_system(pause_toggle.system()))
+            .add_system_set(
+                SystemSet::on_exit(AppState::Paused).with_system(despawn_with::<PauseScreen>.system()),
+            );
+    }
+}
+
+fn menu_setup(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let font = asset_server.load(HUD_FONT);
+    commands
+        .spawn_bundle(screen_text(font.clone(), "RUST INVADERS", 48., 35., 14.))
+        .insert(MenuScreen);
+    commands
+        .spawn_bundle(screen_text(font, "press ENTER or START", 24., 50., 26.))
+        .insert(MenuScreen);
+}
+
+fn menu_start(mut actions: ResMut<Actions>, mut state: ResMut<State<AppState>>) {
+    if actions.just_pressed(Action::Confirm) {
+        // consume the press so the next screen does not see it this frame, START is bound
+        // to pause as well
+        actions.consume(Action::Confirm);
+        actions.consume(Action::Pause);
+        state.set(AppState::Playing).unwrap();
+    }
+}
+
+fn pause_setup(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn_bundle(screen_text(asset_server.load(HUD_FONT), "PAUSED", 40., 45., 36.))
+        .insert(PauseScreen);
+}
+
+fn pause_toggle(mut actions: ResMut<Actions>, mut state: ResMut<State<AppState>>) {
+    if actions.just_pressed(Action::Pause) {
+        actions.consume(Action::Pause);
+        // Paused is pushed on top of Playing so the game world is kept as is
+        match state.current() {
+            AppState::Playing => state.push(AppState::Paused).unwrap(),
+            AppState::Paused => state.pop().unwrap(),
+            _ => {}
+        }
+    }
+}
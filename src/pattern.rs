@@ -0,0 +1,208 @@
+use bevy::prelude::*;
+use crate::defs::{AimDef, BulletDef, PatternDef, ShotDef};
+use crate::{simulation, SimulationStep, FromEnemy, GameClock, Laser, Player, SpriteInfos, FIXED_UPDATE, SCALE};
+
+pub struct PatternPlugin;
+
+// decelerating bullets slow down to a crawl but never stop on screen
+const BULLET_MIN_SPEED: f32 = 30.;
+
+// region:    Bullet
+/// Enemy bullet motion, angles in radians with 0 pointing straight down
+#[derive(Component)]
+pub struct Bullet {
+    angle: f32,
+    speed: f32,
+    acceleration: f32,
+    angular_velocity: f32,
+    drift: Vec2,
+}
+
+impl Bullet {
+    fn new(angle: f32, def: &BulletDef) -> Self {
+        Self {
+            angle,
+            speed: def.speed,
+            acceleration: def.acceleration,
+            angular_velocity: def.angular_velocity.to_radians(),
+            drift: Vec2::ZERO,
+        }
+    }
+
+    pub fn velocity(&self) -> Vec2 {
+        direction(self.angle) * self.speed + self.drift
+    }
+
+    /// Steer the bullet from a script, `drift` is added to the velocity along the heading
+    pub fn set_motion(&mut self, angle: f32, speed: f32, drift: Vec2) {
+        self.angle = angle;
+        self.speed = speed;
+        self.drift = drift;
+    }
+
+    /// Advance the bullet motion by `dt` seconds, returns the distance covered
+    pub fn step(&mut self, dt: f32) -> Vec2 {
+        self.angle += self.angular_velocity * dt;
+        self.speed = (self.speed + self.acceleration * dt).max(BULLET_MIN_SPEED.min(self.speed));
+        self.velocity() * dt
+    }
+
+    /// Sprite rotation pointing the laser where it is going
+    pub fn rotation(&self) -> Quat {
+        let velocity = self.velocity();
+        if velocity == Vec2::ZERO {
+            Quat::from_rotation_z(self.angle)
+        } else {
+            Quat::from_rotation_z(velocity.x.atan2(-velocity.y))
+        }
+    }
+}
+
+fn direction(angle: f32) -> Vec2 {
+    Vec2::new(angle.sin(), -angle.cos())
+}
+
+/// Angle from `from` towards `target`
+fn angle_to(from: Vec3, target: Vec3) -> f32 {
+    let aim = target - from;
+    aim.x.atan2(-aim.y)
+}
+// endregion: Bullet
+
+// region:    Emitter
+struct Volley {
+    pattern: PatternDef,
+    timer: Timer,
+    turn: f32,
+}
+
+/// Fires its patterns at their own interval, each volley turned by the pattern `turn`
+#[derive(Component)]
+pub struct Emitter {
+    volleys: Vec<Volley>,
+}
+
+impl Emitter {
+    pub fn new(patterns: &[PatternDef]) -> Self {
+        Self {
+            volleys: patterns
+                .iter()
+                .map(|pattern| Volley {
+                    pattern: pattern.clone(),
+                    timer: Timer::from_seconds(pattern.interval, true),
+                    turn: 0.,
+                })
+                .collect(),
+        }
+    }
+}
+
+// Emitters fired by other emitters burn out
+#[derive(Component)]
+struct EmitterLifetime(Timer);
+// endregion: Emitter
+
+impl Plugin for PatternPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set_to_stage(
+            FIXED_UPDATE,
+            simulation(SimulationStep::Pattern)
+                .with_system(emitter_fire.system().label("emitter_fire"))
+                .with_system(emitter_expire.system().after("emitter_fire")),
+        );
+    }
+}
+
+fn emitter_fire(
+    mut commands: Commands,
+    clock: Res<GameClock>,
+    textures: Res<SpriteInfos>,
+    player_query: Query<&Transform, With<Player>>,
+    mut query: Query<(&mut Emitter, &Transform)>,
+) {
+    let player_position = player_query.iter().next().map(|tf| tf.translation);
+
+    for (mut emitter, tf) in query.iter_mut() {
+        let position = Vec3::new(tf.translation.x, tf.translation.y, 0.);
+        for volley in emitter.volleys.iter_mut() {
+            if !volley.timer.tick(clock.delta()).just_finished() {
+                continue;
+            }
+            let aim = match (&volley.pattern.aim, player_position) {
+                (AimDef::Player, Some(target)) => angle_to(position, target),
+                _ => 0.,
+            };
+            let angle = aim + volley.turn.to_radians();
+            for shot in &volley.pattern.shots {
+                fire_shot(&mut commands, &textures, position, angle, shot);
+            }
+            volley.turn += volley.pattern.turn;
+        }
+    }
+}
+
+fn fire_shot(commands: &mut Commands, textures: &SpriteInfos, position: Vec3, angle: f32, shot: &ShotDef) {
+    match shot {
+        ShotDef::Spread { count, spread, bullet } => {
+            // bullets evenly spread around the aim
+            let spread = spread.to_radians();
+            for i in 0..*count {
+                let offset = if *count == 1 {
+                    0.
+                } else {
+                    -spread / 2. + spread * i as f32 / (*count - 1) as f32
+                };
+                spawn_bullet(commands, textures, position, angle + offset, bullet);
+            }
+        }
+        ShotDef::Radial { count, bullet } => {
+            let step = std::f32::consts::TAU / *count as f32;
+            for i in 0..*count {
+                spawn_bullet(commands, textures, position, angle + step * i as f32, bullet);
+            }
+        }
+        ShotDef::Emitter { bullet, lifetime, pattern } => {
+            let entity = spawn_bullet(commands, textures, position, angle, bullet);
+            commands
+                .entity(entity)
+                .insert(Emitter::new(std::slice::from_ref(&**pattern)))
+                .insert(EmitterLifetime(Timer::from_seconds(*lifetime, false)));
+        }
+    }
+}
+
+pub fn spawn_bullet(
+    commands: &mut Commands,
+    textures: &SpriteInfos,
+    position: Vec3,
+    angle: f32,
+    def: &BulletDef,
+) -> Entity {
+    let bullet = Bullet::new(angle, def);
+    commands
+        .spawn_bundle(SpriteBundle {
+            texture: textures.enemy_laser.0.clone(),
+            transform: Transform {
+                translation: position,
+                rotation: bullet.rotation(),
+                scale: Vec3::new(SCALE, -SCALE, 1.),
+            },
+            ..Default::default()
+        })
+        .insert(Laser)
+        .insert(FromEnemy)
+        .insert(bullet)
+        .id()
+}
+
+fn emitter_expire(
+    mut commands: Commands,
+    clock: Res<GameClock>,
+    mut query: Query<(Entity, &mut EmitterLifetime)>,
+) {
+    for (entity, mut lifetime) in query.iter_mut() {
+        if lifetime.0.tick(clock.delta()).just_finished() {
+            commands.entity(entity).despawn();
+        }
+    }
+}
@@ -1,46 +1,63 @@
-use bevy::core::FixedTimestep;
 use bevy::prelude::*;
-use crate::{FromPlayer, Laser, Player, PLAYER_LASER_SPRITE, PLAYER_RESPAWN_DELAY, PLAYER_SPRITE, PlayerReadyFire, PlayerState, SCALE, Speed, TIME_STEP, WinSize};
+use crate::controls::PlayerInput;
+use crate::defs::GameDefs;
+use crate::weapon::{fire_charge, fire_weapon, Weapon};
+use crate::{simulation, SimulationStep, AppState, FromEnemy, GameClock, Invulnerable, RESPAWN_CLEAR_RADIUS, RESPAWN_INVULNERABLE_SECONDS, FIXED_UPDATE, FOCUS_SPEED_FACTOR, FOCUS_SPREAD_FACTOR, PLAYER_HITBOX, RAPID_FIRE_FACTOR, SPREAD_SHOT_ANGLE, EXTRA_LIFE_EVERY, FromPlayer, Laser, Player, PLAYER_RESPAWN_DELAY, PLAYER_ROW, PLAYER_SPRITE, PlayField, PlayerState, SCALE, Score, Speed, SpriteInfos, TIME_STEP, WinSize};
 
 pub struct PlayerPlugin;
 
+// The true hitbox, only drawn in focus mode
+#[derive(Component)]
+struct Hitbox;
+
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut App) {
         app
             .insert_resource(PlayerState::default())
-            .add_startup_stage(
-                "game_setup_actors",
-                SystemStage::single(player_spawn.system()),
-            )
-            .add_system(player_movement.system())
-            .add_system(player_fire.system())
-            .add_system(laser_movement.system())
-            .add_system_set(
-                SystemSet::new()
-                    .with_run_criteria(FixedTimestep::step(0.5))
-                    .with_system(player_spawn.system())
+            .add_system_set_to_stage(
+                FIXED_UPDATE,
+                simulation(SimulationStep::Player)
+                    .with_system(player_movement.system().label("player_movement"))
+                    .with_system(player_fire.system().label("player_fire").after("player_movement"))
+                    .with_system(laser_movement.system().label("laser_movement").after("player_fire"))
+                    .with_system(player_extra_life.system().label("player_extra_life").after("laser_movement"))
+                    .with_system(player_spawn.system().label("player_spawn").after("player_extra_life"))
+                    .with_system(hitbox_visibility.system().after("player_spawn"))
             );
 
     }
 }
 
+#[allow(clippy::type_complexity)]
 fn player_spawn(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
-    win_size: Res<WinSize>,
-    time: Res<Time>,
-    mut player_state: ResMut<PlayerState>
+    field: Res<PlayField>,
+    clock: Res<GameClock>,
+    mut player_state: ResMut<PlayerState>,
+    laser_query: Query<(Entity, &Transform), (With<Laser>, With<FromEnemy>)>,
 ) {
-    let now = time.seconds_since_startup();
+    let now = clock.seconds_since_startup();
     let last_shot = player_state.last_shot;
-    let bottom = win_size.h / 2.;
+    let bottom = field.h / 2.;
+
+    if !player_state.is_alive
+        && player_state.lives > 0
+        && (last_shot == 0. || now > last_shot + PLAYER_RESPAWN_DELAY)
+    {
+        let position = Vec3::new(0., -bottom + PLAYER_ROW, 10.);
+        // give the new ship some room
+        for (laser_entity, laser_tf) in laser_query.iter() {
+            if laser_tf.translation.truncate().distance(position.truncate()) < RESPAWN_CLEAR_RADIUS {
+                commands.entity(laser_entity).despawn();
+            }
+        }
 
-    if !player_state.is_alive && (last_shot == 0. || now > last_shot + PLAYER_RESPAWN_DELAY) {
         let image = asset_server.load(PLAYER_SPRITE);
         commands.spawn_bundle(SpriteBundle {
             texture: image,
             transform: Transform {
-                translation: Vec3::new(0., -bottom + 25., 10.),
+                translation: position,
                 scale: Vec3::new(SCALE, SCALE, 0.1),
                 ..Default::default()
             },
@@ -48,24 +65,53 @@ fn player_spawn(
         })
             .insert(Player)
             .insert(Speed::default())
-            .insert(PlayerReadyFire(true));
+            .insert(player_state.weapon.clone())
+            .insert(Invulnerable(Timer::from_seconds(RESPAWN_INVULNERABLE_SECONDS, false)))
+            .with_children(|parent| {
+                parent
+                    .spawn_bundle(SpriteBundle {
+                        sprite: Sprite {
+                            color: Color::rgb(1., 0.2, 0.2),
+                            // the child inherits the ship scale
+                            custom_size: Some(Vec2::splat(PLAYER_HITBOX / SCALE)),
+                            ..Default::default()
+                        },
+                        transform: Transform::from_xyz(0., 0., 1.),
+                        visibility: Visibility { is_visible: false },
+                        ..Default::default()
+                    })
+                    .insert(Hitbox);
+            });
 
         player_state.spawned();
     }
 }
 
+fn player_extra_life(
+    score: Res<Score>,
+    mut player_state: ResMut<PlayerState>
+) {
+    // award a life for every EXTRA_LIFE_EVERY points crossed, not counting grazes
+    while score.value - score.graze_points >= player_state.next_extra_life {
+        player_state.lives += 1;
+        player_state.next_extra_life += EXTRA_LIFE_EVERY;
+    }
+}
+
 fn player_movement(
-    keyboard_input: Res<Input<KeyCode>>,
+    input: Res<PlayerInput>,
+    sprite_infos: Res<SpriteInfos>,
     mut query: Query<(&Speed, &mut Transform), With<Player>>,
-    win_size: Res<WinSize>
+    field: Res<PlayField>
 ) {
-    let x_offset = 100. / 2.;
-    let boundary = win_size.w / 2.;
     if let Ok((speed, mut transform)) = query.get_single_mut() {
-        let direction = if keyboard_input.pressed(KeyCode::A) && (transform.translation.x - x_offset) > -boundary { -1.}
-        else if keyboard_input.pressed(KeyCode::D)  && (transform.translation.x + x_offset) < boundary  { 1.}
-        else { 0.};
-        transform.translation.x += direction * speed.0 * TIME_STEP;
+        let direction = input.movement();
+        let speed = if input.focus { speed.0 * FOCUS_SPEED_FACTOR } else { speed.0 };
+        let size = sprite_infos.player.1 * transform.scale.truncate();
+        let position = transform.translation.truncate() + direction * speed * TIME_STEP;
+        let position = field.clamp(position, size);
+        transform.translation.x = position.x;
+        transform.translation.y = position.y;
     }
 }
 
@@ -73,51 +119,67 @@ fn player_movement(
 
 fn player_fire(
     mut commands: Commands,
-    kb: Res<Input<KeyCode>>,
-    asset_server: Res<AssetServer>,
-    mut query: Query<(&Transform, &mut PlayerReadyFire), With<Player>>
+    input: Res<PlayerInput>,
+    sprite_infos: Res<SpriteInfos>,
+    defs: Res<GameDefs>,
+    player_state: Res<PlayerState>,
+    mut query: Query<(&Transform, &mut Weapon), With<Player>>
 ){
-    if let Ok((player_tf, mut ready_fire)) = query.get_single_mut() {
-        if ready_fire.0 && kb.pressed(KeyCode::Space) {
-            let coords = (player_tf.translation.x, player_tf.translation.y);
-
-            let mut spawn_laser = |x_offset: f32| {
-                let image = asset_server.load(PLAYER_LASER_SPRITE);
-                commands.spawn_bundle(SpriteBundle {
-                    texture: image,
-                    transform: Transform {
-                        translation: Vec3::new(coords.0 + x_offset, coords.1 + 15., 0.),
-                        scale: Vec3::new(0.4, 0.4, 0.1),
-                        ..Default::default()
-                    },
-                    ..Default::default()
-                })
-                    .insert(Laser)
-                    .insert(FromPlayer)
-                    .insert(Speed::default());
-            };
-            let x_offset = 144. / 4. - 5.;
-            spawn_laser(x_offset);
-            spawn_laser(-x_offset);
+    if let Ok((player_tf, mut weapon)) = query.get_single_mut() {
+        weapon.cool_down(TIME_STEP);
+        let def = defs.weapon(weapon.kind());
 
-            ready_fire.0 = false;
+        // weapons with a charge shot build it up while the charge button is held, it fires on release
+        if let Some(charge) = &def.charge {
+            if input.charge {
+                weapon.charge_up(TIME_STEP);
+            } else if input.charge_released() && weapon.release(charge) {
+                fire_charge(&mut commands, &sprite_infos, def, charge, player_tf.translation);
+                let cooldown = def.level(weapon.level()).cooldown;
+                weapon.fired(cooldown);
+            }
         }
 
-        if kb.just_released(KeyCode::Space) {
-            ready_fire.0 = true;
+        // holding fire shoots whenever the weapon has cooled down, charging holds it back
+        if input.fire && weapon.ready() && !weapon.charging() {
+            let level = def.level(weapon.level());
+            let extra_angles: &[f32] = if player_state.spread_shot > 0. {
+                &[SPREAD_SHOT_ANGLE, -SPREAD_SHOT_ANGLE]
+            } else {
+                &[]
+            };
+            let tighten = if input.focus { FOCUS_SPREAD_FACTOR } else { 1. };
+            fire_weapon(&mut commands, &sprite_infos, def, level, player_tf.translation, extra_angles, tighten);
+
+            let cooldown = if player_state.rapid_fire > 0. {
+                level.cooldown * RAPID_FIRE_FACTOR
+            } else {
+                level.cooldown
+            };
+            weapon.fired(cooldown);
         }
     }
 }
 
+fn hitbox_visibility(input: Res<PlayerInput>, mut query: Query<&mut Visibility, With<Hitbox>>) {
+    for mut visibility in query.iter_mut() {
+        visibility.is_visible = input.focus;
+    }
+}
+
+#[allow(clippy::type_complexity)]
 fn laser_movement(
     mut commands: Commands,
-    win_size: Res<WinSize>,
+    field: Res<PlayField>,
     mut query: Query<(Entity, &Speed, &mut Transform), (With<Laser>, With<FromPlayer>)>
 ) {
     for (laser_entity, speed, mut laser_tf) in query.iter_mut() {
+        // lasers fly along their own up axis
+        let heading = laser_tf.rotation * Vec3::Y;
         let translation = &mut laser_tf.translation;
-        translation.y += speed.0 * TIME_STEP;
-        if translation.y > win_size.h {
+        *translation += heading * speed.0 * TIME_STEP;
+        // homing shots can turn back down, so every edge counts
+        if translation.y.abs() > field.h / 2. + 50. || translation.x.abs() > field.w / 2. + 50. {
             commands.entity(laser_entity).despawn();
         }
     }
@@ -0,0 +1,181 @@
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+use bevy::sprite::collide_aabb::collide;
+use rand::Rng;
+use serde::Deserialize;
+use crate::defs::{DropTable, GameDefs};
+use crate::weapon::{Weapon, WeaponKind};
+use crate::{
+    simulation, SimulationStep, EnemyKilled, GameRng, Player, PlayerState, Score, Speed, SpriteInfos, WinSize, FIXED_UPDATE,
+    BOMB_ITEM_POINTS, MAX_BOMBS, PICKUP_SPEED, POWERUP_SECONDS, SCORE_GEM_POINTS, SHIELD_SECONDS, TIME_STEP,
+};
+
+pub struct PowerupPlugin;
+
+const PICKUP_SIZE: f32 = 18.;
+const SCORE_ITEM_SIZE: f32 = 8.;
+const SHIELD_COLOR: Color = Color::rgb(0.5, 0.8, 1.);
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum Powerup {
+    SpreadShot,
+    RapidFire,
+    Shield,
+    ExtraLife,
+    Bomb,
+    ScoreGem,
+    /// what enemy lasers turn into when a bomb goes off
+    ScoreItem,
+    Weapon(WeaponKind),
+}
+
+impl Powerup {
+    fn color(&self) -> Color {
+        match self {
+            Powerup::SpreadShot => Color::rgb(1., 0.6, 0.1),
+            Powerup::RapidFire => Color::rgb(1., 0.2, 0.2),
+            Powerup::Shield => SHIELD_COLOR,
+            Powerup::ExtraLife => Color::rgb(0.3, 1., 0.3),
+            Powerup::Bomb => Color::rgb(0.8, 0.3, 1.),
+            Powerup::ScoreGem => Color::rgb(1., 1., 0.3),
+            Powerup::ScoreItem => Color::rgb(0.3, 0.6, 1.),
+            Powerup::Weapon(kind) => match kind {
+                WeaponKind::Twin => Color::rgb(1., 0.4, 0.4),
+                WeaponKind::Spread => Color::rgb(0.4, 1., 0.6),
+                WeaponKind::Beam => Color::rgb(0.4, 0.7, 1.),
+                WeaponKind::Homing => Color::rgb(1., 0.5, 1.),
+            },
+        }
+    }
+
+    fn size(&self) -> f32 {
+        match self {
+            Powerup::ScoreItem => SCORE_ITEM_SIZE,
+            _ => PICKUP_SIZE,
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct Pickup(Powerup);
+
+impl Plugin for PowerupPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set_to_stage(
+            FIXED_UPDATE,
+            simulation(SimulationStep::Powerup)
+                .with_system(powerup_drop.system().label("powerup_drop").after("collisions"))
+                .with_system(pickup_movement.system().label("pickup_movement").after("powerup_drop"))
+                .with_system(pickup_collect.system().label("pickup_collect").after("pickup_movement"))
+                .with_system(powerup_timers.system().after("pickup_collect")),
+        );
+    }
+}
+
+/// Weighted pick from the drop table, `None` most of the time
+fn roll(table: &DropTable, rng: &mut impl Rng) -> Option<Powerup> {
+    if !rng.gen_bool(table.chance as f64) {
+        return None;
+    }
+    let total: u32 = table.drops.iter().map(|drop| drop.weight).sum();
+    let mut pick = rng.gen_range(0..total);
+    for drop in &table.drops {
+        if pick < drop.weight {
+            return Some(drop.powerup);
+        }
+        pick -= drop.weight;
+    }
+    None
+}
+
+fn powerup_drop(
+    mut commands: Commands,
+    mut game_rng: ResMut<GameRng>,
+    defs: Res<GameDefs>,
+    mut enemy_killed: EventReader<EnemyKilled>,
+) {
+    for event in enemy_killed.iter() {
+        if let Some(powerup) = roll(&defs.drops, &mut game_rng.rng) {
+            spawn_pickup(&mut commands, powerup, event.position);
+        }
+    }
+}
+
+pub fn spawn_pickup(commands: &mut Commands, powerup: Powerup, position: Vec3) {
+    commands
+        .spawn_bundle(SpriteBundle {
+            sprite: Sprite {
+                color: powerup.color(),
+                custom_size: Some(Vec2::splat(powerup.size())),
+                ..Default::default()
+            },
+            transform: Transform {
+                translation: Vec3::new(position.x, position.y, 5.),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .insert(Pickup(powerup))
+        .insert(Speed(PICKUP_SPEED));
+}
+
+fn pickup_movement(
+    mut commands: Commands,
+    win_size: Res<WinSize>,
+    mut query: Query<(Entity, &Speed, &mut Transform), With<Pickup>>,
+) {
+    for (entity, speed, mut tf) in query.iter_mut() {
+        tf.translation.y -= speed.0 * TIME_STEP;
+        if tf.translation.y < -win_size.h / 2. - 50. {
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+fn pickup_collect(
+    mut commands: Commands,
+    sprite_infos: Res<SpriteInfos>,
+    defs: Res<GameDefs>,
+    mut player_state: ResMut<PlayerState>,
+    mut score: ResMut<Score>,
+    mut player_query: Query<(&Transform, &mut Weapon), With<Player>>,
+    pickup_query: Query<(Entity, &Transform, &Pickup)>,
+) {
+    if let Ok((player_tf, mut weapon)) = player_query.get_single_mut() {
+        let player_size = sprite_infos.player.1 * player_tf.scale.xy();
+        for (entity, tf, pickup) in pickup_query.iter() {
+            let collision = collide(tf.translation, Vec2::splat(pickup.0.size()), player_tf.translation, player_size);
+            if collision.is_none() {
+                continue;
+            }
+            match pickup.0 {
+                Powerup::SpreadShot => player_state.spread_shot = POWERUP_SECONDS,
+                Powerup::RapidFire => player_state.rapid_fire = POWERUP_SECONDS,
+                Powerup::Shield => player_state.shield = SHIELD_SECONDS,
+                Powerup::ExtraLife => player_state.lives += 1,
+                Powerup::Bomb => player_state.bombs = (player_state.bombs + 1).min(MAX_BOMBS),
+                Powerup::ScoreGem => score.add_bonus(SCORE_GEM_POINTS),
+                Powerup::ScoreItem => score.add_bonus(BOMB_ITEM_POINTS),
+                Powerup::Weapon(kind) => {
+                    let max_level = defs.weapon(kind).levels.len() as u32;
+                    weapon.upgrade(kind, max_level);
+                }
+            }
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+/// Run down the timed power-ups, the ship is tinted while its shield is up
+fn powerup_timers(mut player_state: ResMut<PlayerState>, mut query: Query<&mut Sprite, With<Player>>) {
+    if !player_state.is_alive {
+        return;
+    }
+    player_state.spread_shot = (player_state.spread_shot - TIME_STEP).max(0.);
+    player_state.rapid_fire = (player_state.rapid_fire - TIME_STEP).max(0.);
+    player_state.shield = (player_state.shield - TIME_STEP).max(0.);
+
+    for mut sprite in query.iter_mut() {
+        sprite.color = if player_state.shield > 0. { SHIELD_COLOR } else { Color::WHITE };
+    }
+}
@@ -0,0 +1,200 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::controls::PlayerInput;
+use crate::{run_if_playing, AppState, GameMode, PlayField, WinSize, FIXED_UPDATE, INPUT, SIMULATE};
+
+const REPLAY_VERSION: u32 = 5;
+
+// region:    Replay
+/// A recorded run: the rng seed, game mode, window and play field sizes
+/// plus the movement and button bits of every simulation tick
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Replay {
+    pub version: u32,
+    pub seed: u64,
+    #[serde(default)]
+    pub mode: GameMode,
+    pub size: (f32, f32),
+    pub field: (f32, f32),
+    pub frames: Vec<(i8, i8, u8)>,
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, ron::Error),
+    Version(PathBuf, u32),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io(path, err) => write!(f, "cannot access replay {}: {}", path.display(), err),
+            ReplayError::Parse(path, err) => write!(f, "cannot parse replay {}: {}", path.display(), err),
+            ReplayError::Version(path, version) => write!(
+                f,
+                "replay {} has version {}, this build plays version {}",
+                path.display(),
+                version,
+                REPLAY_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl Replay {
+    pub fn load(path: &Path) -> Result<Self, ReplayError> {
+        let text = std::fs::read_to_string(path).map_err(|err| ReplayError::Io(path.to_path_buf(), err))?;
+        let replay: Replay = ron::from_str(&text).map_err(|err| ReplayError::Parse(path.to_path_buf(), err))?;
+        if replay.version != REPLAY_VERSION {
+            return Err(ReplayError::Version(path.to_path_buf(), replay.version));
+        }
+        Ok(replay)
+    }
+
+    fn save(&self, path: &Path) -> Result<(), ReplayError> {
+        let io_err = |err| ReplayError::Io(path.to_path_buf(), err);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(io_err)?;
+        }
+        let text = ron::to_string(self).expect("replay is always serializable");
+        std::fs::write(path, text).map_err(io_err)
+    }
+}
+// endregion: Replay
+
+pub enum ReplayMode {
+    Record(PathBuf),
+    Playback(Replay),
+}
+
+// Resource
+struct ReplayRecorder {
+    path: PathBuf,
+    replay: Replay,
+}
+
+// Resource
+struct ReplayPlayback {
+    replay: Replay,
+    cursor: usize,
+}
+
+/// Record every run to a replay file, or feed the input of a recorded run back tick by tick
+pub fn add_replay(app: &mut App, mode: ReplayMode, seed: u64) {
+    match mode {
+        ReplayMode::Record(path) => {
+            app
+                .insert_resource(ReplayRecorder {
+                    path,
+                    replay: Replay {
+                        version: REPLAY_VERSION,
+                        seed,
+                        mode: GameMode::default(),
+                        size: (0., 0.),
+                        field: (0., 0.),
+                        frames: Vec::new(),
+                    },
+                })
+                .add_system_set(SystemSet::on_enter(AppState::Playing).with_system(replay_start.system()))
+                .add_system_set(SystemSet::on_exit(AppState::Playing).with_system(replay_save.system()))
+                .add_system_set_to_stage(
+                    FIXED_UPDATE,
+                    SystemSet::new()
+                        .with_run_criteria(run_if_playing.system())
+                        .after(SIMULATE)
+                        .with_system(replay_record.system()),
+                )
+                .add_system_to_stage(CoreStage::Last, replay_save_on_exit.system());
+        }
+        ReplayMode::Playback(replay) => {
+            app
+                .insert_resource(ReplayPlayback { replay, cursor: 0 })
+                .add_system_set(SystemSet::on_update(AppState::Menu).with_system(replay_begin.system()))
+                .add_system_set_to_stage(
+                    FIXED_UPDATE,
+                    SystemSet::new()
+                        .with_run_criteria(run_if_playing.system())
+                        .label(INPUT)
+                        .after("devices")
+                        .with_system(replay_playback.system()),
+                );
+        }
+    }
+}
+
+fn replay_start(
+    mut recorder: ResMut<ReplayRecorder>,
+    mode: Res<GameMode>,
+    win_size: Res<WinSize>,
+    field: Res<PlayField>,
+) {
+    recorder.replay.mode = *mode;
+    recorder.replay.size = (win_size.w, win_size.h);
+    recorder.replay.field = (field.w, field.h);
+    recorder.replay.frames.clear();
+}
+
+fn replay_record(mut recorder: ResMut<ReplayRecorder>, input: Res<PlayerInput>) {
+    let frame = input.to_frame();
+    recorder.replay.frames.push(frame);
+}
+
+impl ReplayRecorder {
+    fn save(&self) {
+        if self.replay.frames.is_empty() {
+            return;
+        }
+        match self.replay.save(&self.path) {
+            Ok(()) => println!("replay saved to {}", self.path.display()),
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+}
+
+fn replay_save(recorder: Res<ReplayRecorder>) {
+    recorder.save();
+}
+
+fn replay_save_on_exit(recorder: Res<ReplayRecorder>, mut exit: EventReader<AppExit>) {
+    if exit.iter().next().is_some() {
+        replay_save(recorder);
+    }
+}
+
+/// Save the recording of a run that stops without leaving `Playing` or an `AppExit`,
+/// like a headless run out of ticks
+pub fn save_recording(world: &World) {
+    if let Some(recorder) = world.get_resource::<ReplayRecorder>() {
+        recorder.save();
+    }
+}
+
+/// Start the run right away, like the recorded run did when it left the menu
+fn replay_begin(playback: Res<ReplayPlayback>, mut state: ResMut<State<AppState>>) {
+    if playback.cursor == 0 {
+        state.set(AppState::Playing).unwrap();
+    }
+}
+
+fn replay_playback(
+    mut playback: ResMut<ReplayPlayback>,
+    mut input: ResMut<PlayerInput>,
+    mut exit: EventWriter<AppExit>,
+) {
+    match playback.replay.frames.get(playback.cursor) {
+        Some(&frame) => {
+            input.apply_frame(frame);
+            playback.cursor += 1;
+        }
+        None => {
+            println!("replay finished after {} ticks", playback.cursor);
+            exit.send(AppExit);
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use bevy::prelude::*;
+use crate::{simulation, SimulationStep, EnemyKilled, Score, FIXED_UPDATE};
+
+pub struct ScorePlugin;
+
+impl Plugin for ScorePlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(Score::default())
+            .add_event::<EnemyKilled>()
+            .add_system_set_to_stage(
+                FIXED_UPDATE,
+                simulation(SimulationStep::Score).with_system(score_enemy_killed.system().after("collisions")),
+            );
+    }
+}
+
+fn score_enemy_killed(
+    mut score: ResMut<Score>,
+    mut enemy_killed: EventReader<EnemyKilled>,
+) {
+    for event in enemy_killed.iter() {
+        score.add_kill(event.points);
+    }
+}
@@ -0,0 +1,91 @@
+use bevy::ecs::schedule::ShouldRun;
+use bevy::ecs::system::Command;
+use bevy::prelude::*;
+use bevy::transform::TransformSystem;
+use crate::pattern::Bullet;
+use crate::{GameClock, Speed, FIXED_UPDATE, SIMULATE};
+
+pub struct TimestepPlugin;
+
+/// Simulated translation of a moving entity at the previous and the latest tick,
+/// the rendered transform is interpolated in between
+#[derive(Component)]
+pub struct Interpolated {
+    previous: Vec3,
+    current: Vec3,
+}
+
+impl Interpolated {
+    /// Translation at the start of the tick while the simulation runs
+    pub fn previous(&self) -> Vec3 {
+        self.previous
+    }
+}
+
+impl Plugin for TimestepPlugin {
+    fn build(&self, app: &mut App) {
+        // single threaded so a seed and input sequence always run the systems in the same order
+        app
+            .add_stage_before(
+                CoreStage::Update,
+                FIXED_UPDATE,
+                SystemStage::single_threaded().with_run_criteria(fixed_tick.system()),
+            )
+            .add_system_to_stage(FIXED_UPDATE, interpolation_restore.system().before(SIMULATE))
+            .add_system_to_stage(FIXED_UPDATE, interpolation_store.system().after(SIMULATE))
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                interpolation_apply.system().before(TransformSystem::TransformPropagate),
+            );
+    }
+}
+
+/// Run the fixed stage once per whole `TIME_STEP` accumulated by the game clock
+fn fixed_tick(mut clock: ResMut<GameClock>) -> ShouldRun {
+    if clock.consume_tick() {
+        ShouldRun::YesAndCheckAgain
+    } else {
+        ShouldRun::No
+    }
+}
+
+fn interpolation_restore(mut query: Query<(&mut Transform, &mut Interpolated)>) {
+    for (mut tf, mut interpolated) in query.iter_mut() {
+        tf.translation = interpolated.current;
+        interpolated.previous = interpolated.current;
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn interpolation_store(
+    mut commands: Commands,
+    mut query: Query<(Entity, &Transform, Option<&mut Interpolated>), Or<(With<Speed>, With<Bullet>)>>,
+) {
+    for (entity, tf, interpolated) in query.iter_mut() {
+        match interpolated {
+            Some(mut interpolated) => interpolated.current = tf.translation,
+            None => commands.add(StartInterpolation(entity, tf.translation)),
+        }
+    }
+}
+
+/// Insert `Interpolated` unless the entity was despawned earlier in the same tick
+struct StartInterpolation(Entity, Vec3);
+
+impl Command for StartInterpolation {
+    fn write(self, world: &mut World) {
+        if let Some(mut entity) = world.get_entity_mut(self.0) {
+            entity.insert(Interpolated {
+                previous: self.1,
+                current: self.1,
+            });
+        }
+    }
+}
+
+fn interpolation_apply(clock: Res<GameClock>, mut query: Query<(&mut Transform, &Interpolated)>) {
+    let alpha = clock.overstep();
+    for (mut tf, interpolated) in query.iter_mut() {
+        tf.translation = interpolated.previous.lerp(interpolated.current, alpha);
+    }
+}
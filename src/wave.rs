@@ -0,0 +1,193 @@
+use bevy::prelude::*;
+use crate::hud::screen_text;
+use crate::defs::{GameDefs, GridDef, WaveDef, WaveScript};
+use crate::{
+    despawn_with, simulation, SimulationStep, ActiveEnemies, AppState, GameClock, GameMode, FIXED_UPDATE, HUD_FONT, RANK_WAVES,
+    WAVE_BANNER, WAVE_BREAK,
+};
+
+pub struct WavePlugin;
+
+// region:    Wave
+// Resource
+pub struct WaveDirector {
+    wave: u32,
+    spec: WaveDef,
+    speed_bonus: f32,
+    group: usize,
+    group_spawned: u32,
+    grid: Option<GridDef>,
+    boss: Option<String>,
+    intermission: Timer,
+}
+
+impl Default for WaveDirector {
+    fn default() -> Self {
+        Self {
+            wave: 0,
+            spec: WaveDef::default(),
+            speed_bonus: 1.,
+            group: 0,
+            group_spawned: 0,
+            grid: None,
+            boss: None,
+            intermission: Timer::from_seconds(WAVE_BREAK / 2., false),
+        }
+    }
+}
+
+impl WaveDirector {
+    /// Reserve the next enemy of the wave, returns whether it opens a new formation
+    /// and the archetype to spawn
+    pub fn next_member(&mut self) -> Option<(bool, String)> {
+        if !self.intermission.finished() {
+            return None;
+        }
+        let formation = self.spec.formations.get(self.group)?;
+        let archetype = formation.enemy.clone();
+        let new_formation = self.group_spawned == 0;
+        self.group_spawned += 1;
+        if self.group_spawned >= formation.members {
+            self.group += 1;
+            self.group_spawned = 0;
+        }
+        Some((new_formation, archetype))
+    }
+
+    pub fn max_active(&self) -> u32 {
+        self.spec.max_active
+    }
+
+    /// Speed multiplier, grows each time the wave script loops
+    pub fn speed_bonus(&self) -> f32 {
+        self.speed_bonus
+    }
+
+    fn enemy_count(&self) -> u32 {
+        let grid = self
+            .spec
+            .grid
+            .as_ref()
+            .map_or(0, |grid| grid.rows.len() as u32 * grid.columns);
+        self.spec.formations.iter().map(|formation| formation.members).sum::<u32>() + grid
+    }
+
+    pub fn wave(&self) -> u32 {
+        self.wave
+    }
+
+    /// Difficulty from 0 to 1, grows with every wave
+    pub fn rank(&self) -> f32 {
+        (self.wave.saturating_sub(1) as f32 / RANK_WAVES).min(1.)
+    }
+
+    fn formations_spawned(&self) -> bool {
+        self.group >= self.spec.formations.len() && self.grid.is_none()
+    }
+
+    fn all_spawned(&self) -> bool {
+        self.formations_spawned() && self.boss.is_none()
+    }
+
+    /// The wave grid, handed out once when the wave starts
+    pub fn take_grid(&mut self) -> Option<GridDef> {
+        if self.intermission.finished() {
+            self.grid.take()
+        } else {
+            None
+        }
+    }
+
+    /// The wave boss, handed out once every formation of the wave has been spawned
+    pub fn take_boss(&mut self) -> Option<String> {
+        if self.formations_spawned() {
+            self.boss.take()
+        } else {
+            None
+        }
+    }
+
+    fn start_next(&mut self, script: &WaveScript) {
+        let index = self.wave as usize % script.waves.len();
+        let loops = self.wave as usize / script.waves.len();
+        self.wave += 1;
+        self.spec = script.waves[index].clone();
+        self.speed_bonus = 1. + script.loop_speed_bonus * loops as f32;
+        self.group = 0;
+        self.group_spawned = 0;
+        self.grid = self.spec.grid.clone();
+        self.boss = self.spec.boss.clone();
+    }
+}
+// endregion: Wave
+
+#[derive(Component)]
+struct WaveBanner;
+
+impl Plugin for WavePlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(WaveDirector::default())
+            .add_system_set(SystemSet::on_enter(AppState::Playing).with_system(wave_reset.system()))
+            .add_system_set_to_stage(FIXED_UPDATE, simulation(SimulationStep::Wave).with_system(wave_progress.system()))
+            .add_system_set(SystemSet::on_update(AppState::Playing).with_system(wave_banner.system()))
+            .add_system_set(
+                SystemSet::on_exit(AppState::Playing).with_system(despawn_with::<WaveBanner>.system()),
+            );
+    }
+}
+
+fn wave_reset(mut wave_director: ResMut<WaveDirector>) {
+    *wave_director = WaveDirector::default();
+}
+
+fn wave_progress(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    clock: Res<GameClock>,
+    active_enemies: Res<ActiveEnemies>,
+    defs: Res<GameDefs>,
+    mode: Res<GameMode>,
+    mut wave_director: ResMut<WaveDirector>,
+) {
+    let font = asset_server.load(HUD_FONT);
+
+    // intermission between waves, start the next one when it is over
+    if !wave_director.intermission.finished() {
+        if wave_director.intermission.tick(clock.delta()).just_finished() {
+            wave_director.start_next(defs.script(*mode));
+            let banner = format!(
+                "WAVE {}  -  {} enemies",
+                wave_director.wave,
+                wave_director.enemy_count()
+            );
+            commands
+                .spawn_bundle(screen_text(font, &banner, 32., 30., 18.))
+                .insert(WaveBanner)
+                .insert(Timer::from_seconds(WAVE_BANNER, false));
+        }
+        return;
+    }
+
+    // everything spawned and destroyed, the wave is cleared
+    if wave_director.all_spawned() && active_enemies.0 == 0 {
+        let banner = format!("WAVE {} CLEARED", wave_director.wave);
+        commands
+            .spawn_bundle(screen_text(font, &banner, 32., 30., 22.))
+            .insert(WaveBanner)
+            .insert(Timer::from_seconds(WAVE_BANNER, false));
+        wave_director.intermission = Timer::from_seconds(WAVE_BREAK, false);
+    }
+}
+
+fn wave_banner(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut query: Query<(Entity, &mut Timer), With<WaveBanner>>,
+) {
+    for (entity, mut timer) in query.iter_mut() {
+        if timer.tick(time.delta()).finished() {
+            commands.entity(entity).despawn();
+        }
+    }
+}
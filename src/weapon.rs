@@ -0,0 +1,224 @@
+use bevy::prelude::*;
+use serde::Deserialize;
+use crate::defs::{ChargeDef, WeaponDef, WeaponLevelDef};
+use crate::{simulation, SimulationStep, Damage, Enemy, FromPlayer, Laser, Speed, SpriteInfos, SpriteSize, FIXED_UPDATE, TIME_STEP};
+
+pub struct WeaponPlugin;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+pub enum WeaponKind {
+    Twin,
+    Spread,
+    Beam,
+    Homing,
+}
+
+// region:    Weapon
+/// Player weapon, fires whenever its cooldown is over
+#[derive(Component, Debug, Clone)]
+pub struct Weapon {
+    kind: WeaponKind,
+    level: u32,
+    cooldown: f32,
+    // seconds the charge button has been held
+    charge: f32,
+}
+
+impl Weapon {
+    pub fn new(kind: WeaponKind) -> Self {
+        Self {
+            kind,
+            level: 1,
+            cooldown: 0.,
+            charge: 0.,
+        }
+    }
+
+    pub fn kind(&self) -> WeaponKind {
+        self.kind
+    }
+
+    pub fn level(&self) -> u32 {
+        self.level
+    }
+
+    /// The same kind picked up again levels the weapon up, another kind is swapped in at the same level
+    pub fn upgrade(&mut self, kind: WeaponKind, max_level: u32) {
+        if kind == self.kind {
+            self.level += 1;
+        }
+        self.kind = kind;
+        self.level = self.level.min(max_level);
+        self.charge = 0.;
+    }
+
+    /// The weapon the ship respawns with after being shot down
+    pub fn downgraded(&self) -> Self {
+        Self {
+            kind: self.kind,
+            level: self.level.saturating_sub(1).max(1),
+            cooldown: 0.,
+            charge: 0.,
+        }
+    }
+
+    pub fn cool_down(&mut self, seconds: f32) {
+        self.cooldown = (self.cooldown - seconds).max(0.);
+    }
+
+    pub fn ready(&self) -> bool {
+        self.cooldown <= 0.
+    }
+
+    pub fn fired(&mut self, cooldown: f32) {
+        self.cooldown = cooldown;
+    }
+
+    pub fn charging(&self) -> bool {
+        self.charge > 0.
+    }
+
+    pub fn charge_up(&mut self, seconds: f32) {
+        self.charge += seconds;
+    }
+
+    /// Let go of the charge, true when it was held long enough to fire
+    pub fn release(&mut self, def: &ChargeDef) -> bool {
+        let charged = self.charge >= def.seconds;
+        self.charge = 0.;
+        charged
+    }
+}
+
+impl Default for Weapon {
+    fn default() -> Self {
+        Self::new(WeaponKind::Twin)
+    }
+}
+// endregion: Weapon
+
+// Piercing lasers go through enemies, damaging each one once
+#[derive(Component, Default)]
+pub struct Piercing(pub Vec<Entity>);
+
+// Homing missiles turn towards the closest enemy, in degrees per second
+#[derive(Component)]
+struct Homing(f32);
+
+impl Plugin for WeaponPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set_to_stage(FIXED_UPDATE, simulation(SimulationStep::Weapon).with_system(homing_steer.system()));
+    }
+}
+
+/// Fire one volley of the weapon level: `count` shots `spacing` apart, fanned out over `spread` degrees,
+/// `extra_angles` adds single shots from the middle of the ship. `tighten` scales spacing and angles down.
+pub fn fire_weapon(
+    commands: &mut Commands,
+    textures: &SpriteInfos,
+    def: &WeaponDef,
+    level: &WeaponLevelDef,
+    position: Vec3,
+    extra_angles: &[f32],
+    tighten: f32,
+) {
+    let count = level.count;
+    for i in 0..count {
+        let (offset, angle) = if count == 1 {
+            (0., 0.)
+        } else {
+            let t = i as f32 / (count - 1) as f32 - 0.5;
+            (t * level.spacing * (count - 1) as f32, t * level.spread)
+        };
+        let offset = offset * tighten;
+        spawn_player_laser(commands, textures, def, level, position + Vec3::X * offset, angle * tighten);
+    }
+    for angle in extra_angles {
+        spawn_player_laser(commands, textures, def, level, position, angle * tighten);
+    }
+}
+
+/// Single piercing shot fired by a full charge
+pub fn fire_charge(commands: &mut Commands, textures: &SpriteInfos, def: &WeaponDef, charge: &ChargeDef, position: Vec3) {
+    let (texture, size) = textures.weapons[&def.kind].clone();
+    let (r, g, b) = def.color;
+    commands
+        .spawn_bundle(SpriteBundle {
+            texture,
+            sprite: Sprite {
+                color: Color::rgb(r, g, b),
+                ..Default::default()
+            },
+            transform: Transform {
+                translation: Vec3::new(position.x, position.y + 15., 0.),
+                scale: Vec3::new(charge.scale.0, charge.scale.1, 0.1),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .insert(Laser)
+        .insert(FromPlayer)
+        .insert(Damage(charge.damage))
+        .insert(SpriteSize(size))
+        .insert(Speed(charge.speed))
+        .insert(Piercing::default());
+}
+
+fn spawn_player_laser(
+    commands: &mut Commands,
+    textures: &SpriteInfos,
+    def: &WeaponDef,
+    level: &WeaponLevelDef,
+    position: Vec3,
+    angle: f32,
+) {
+    let (texture, size) = textures.weapons[&def.kind].clone();
+    let (r, g, b) = def.color;
+    let mut laser = commands.spawn_bundle(SpriteBundle {
+        texture,
+        sprite: Sprite {
+            color: Color::rgb(r, g, b),
+            ..Default::default()
+        },
+        transform: Transform {
+            translation: Vec3::new(position.x, position.y + 15., 0.),
+            rotation: Quat::from_rotation_z(angle.to_radians()),
+            scale: Vec3::new(def.scale.0, def.scale.1, 0.1),
+        },
+        ..Default::default()
+    });
+    laser
+        .insert(Laser)
+        .insert(FromPlayer)
+        .insert(Damage(level.damage))
+        .insert(SpriteSize(size))
+        .insert(Speed(level.speed));
+    if def.piercing {
+        laser.insert(Piercing::default());
+    }
+    if def.homing > 0. {
+        laser.insert(Homing(def.homing));
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn homing_steer(
+    enemy_query: Query<&Transform, With<Enemy>>,
+    mut query: Query<(&mut Transform, &Homing), (With<FromPlayer>, Without<Enemy>)>,
+) {
+    for (mut tf, homing) in query.iter_mut() {
+        let position = tf.translation.truncate();
+        let closest = enemy_query
+            .iter()
+            .map(|enemy_tf| enemy_tf.translation.truncate() - position)
+            .filter(|to_enemy| *to_enemy != Vec2::ZERO)
+            .min_by(|a, b| a.length_squared().partial_cmp(&b.length_squared()).unwrap());
+        if let Some(to_enemy) = closest {
+            // player lasers fly along their up axis
+            let heading = (tf.rotation * Vec3::Y).truncate();
+            let turn = heading.angle_between(to_enemy);
+            let max_turn = homing.0.to_radians() * TIME_STEP;
+            tf.rotate(Quat::from_rotation_z(turn.clamp(-max_turn, max_turn)));
+        }
+    }
+}